@@ -1,11 +1,43 @@
+use std::io::Read;
+use std::fs::File;
+use std::path::PathBuf;
 use uuid::Uuid;
+// to make from_hex on strings work
+use rustc_serialize::hex::FromHex;
 
 use conf;
 
 #[derive(Clone)]
 pub struct GeneralConf {
+    pub bind_address: String,
     pub port: i64,
     pub db_name: String,
+    // how long to remember deliveries we've processed, to ignore replays
+    pub delivery_retention_secs: i64,
+    // serve https with these directly, instead of plain http
+    pub tls: Option<TlsConf>,
+    // paths we listen on, without leading or trailing slashes
+    pub message_path: String,
+    pub issue_path: String,
+    pub oauth_callback_path: String,
+}
+
+#[derive(Clone)]
+pub struct TlsConf {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+// A week
+static DEFAULT_DELIVERY_RETENTION_SECS: i64 = 7 * 24 * 60 * 60;
+
+static DEFAULT_BIND_ADDRESS: &'static str = "localhost";
+
+// Configured route paths may be written with or without slashes
+fn route_path(conf: &conf::TomlConf, key: &str, default: &str) -> String {
+    conf::get_conf_val(conf, "general", key)
+        .map(|p| p.trim_matches('/').to_owned())
+        .unwrap_or(default.to_owned())
 }
 
 #[derive(Clone)]
@@ -17,17 +49,61 @@ pub struct BraidConf {
     pub token: String,
 }
 
+// Authenticating as a github app, rather than with per-repo tokens
+#[derive(Clone)]
+pub struct GithubAppConf {
+    pub app_id: i64,
+    // PEM-encoded private key for the app
+    pub private_key: Vec<u8>,
+    // tag for threads from repos the app is installed on, but that aren't
+    // listed in the conf; if unset those repos are ignored
+    pub default_tag_id: Option<Uuid>,
+}
+
 #[derive(Clone)]
 pub struct GithubConf {
     pub webhook_secret: String,
+    // accept webhooks only signed with the legacy SHA-1 X-Hub-Signature
+    pub allow_sha1: bool,
+    pub app: Option<GithubAppConf>,
 }
 
 #[derive(Clone)]
 pub struct RepoConf {
+    // empty when using a github app; filled in with an installation token
+    // by github_app::with_installation_tokens
     pub token: String,
     pub org: String,
     pub repo: String,
     pub tag_id: Uuid,
+    // which label/assignee/milestone changes get posted to threads
+    pub announce: Vec<String>,
+}
+
+static ANNOUNCEABLE_ACTIONS: &'static [&'static str] = &[
+    "labeled", "unlabeled", "assigned", "unassigned", "milestoned",
+    "demilestoned"];
+
+pub fn default_announce() -> Vec<String> {
+    ANNOUNCEABLE_ACTIONS.iter().map(|a| a.to_string()).collect()
+}
+
+// Github OAuth app, for letting users act on github as themselves
+#[derive(Clone)]
+pub struct OauthConf {
+    pub client_id: String,
+    pub client_secret: String,
+    // the public url of our oauth callback route
+    pub redirect_url: String,
+    // AES-256 key for encrypting users' tokens in the database
+    pub encryption_key: Vec<u8>,
+}
+
+// Ties a braid user to their github account
+#[derive(Clone)]
+pub struct UserConf {
+    pub braid_id: Uuid,
+    pub github_login: String,
 }
 
 #[derive(Clone)]
@@ -36,6 +112,8 @@ pub struct AppConf {
     pub braid: BraidConf,
     pub github: GithubConf,
     pub repos: Vec<RepoConf>,
+    pub users: Vec<UserConf>,
+    pub oauth: Option<OauthConf>,
 }
 
 pub fn load_conf(conf_filename: &str) -> AppConf {
@@ -47,9 +125,28 @@ pub fn load_conf(conf_filename: &str) -> AppConf {
                                 "site_url"]);
     conf::validate_conf_group(&conf, "github", &["webhook_secret"]);
     // Can unwrap below, since we've validated keys up here
+    let tls = match (conf::get_conf_val(&conf, "general", "tls_cert"),
+                     conf::get_conf_val(&conf, "general", "tls_key")) {
+        (Some(cert), Some(key)) => Some(TlsConf {
+            cert_path: PathBuf::from(cert),
+            key_path: PathBuf::from(key),
+        }),
+        (None, None) => None,
+        _ => panic!("general tls_cert and tls_key must be set together"),
+    };
     let general = GeneralConf {
+        bind_address: conf::get_conf_val(&conf, "general", "bind_address")
+            .unwrap_or(DEFAULT_BIND_ADDRESS.to_owned()),
         port: conf::get_conf_val_n(&conf, "general", "port").unwrap(),
         db_name: conf::get_conf_val(&conf, "general", "db_name").unwrap(),
+        delivery_retention_secs: conf::get_conf_val_n(&conf, "general",
+                                                      "delivery_retention_secs")
+            .unwrap_or(DEFAULT_DELIVERY_RETENTION_SECS),
+        tls: tls,
+        message_path: route_path(&conf, "message_path", "message"),
+        issue_path: route_path(&conf, "issue_path", "issue"),
+        oauth_callback_path: route_path(&conf, "oauth_callback_path",
+                                        "oauth/callback"),
     };
     let braid = BraidConf {
         name: conf::get_conf_val(&conf, "braid", "name")
@@ -63,10 +160,29 @@ pub fn load_conf(conf_filename: &str) -> AppConf {
         token: conf::get_conf_val(&conf, "braid", "token")
             .unwrap().to_owned(),
     };
+    let app = conf::get_conf_val(&conf, "github", "private_key").map(|key_file| {
+        let mut private_key = vec![];
+        File::open(&key_file[..])
+            .and_then(|mut f| f.read_to_end(&mut private_key))
+            .expect("Couldn't read github app private key");
+        GithubAppConf {
+            app_id: conf::get_conf_val_n(&conf, "github", "app_id")
+                .expect("Missing github app_id for private_key"),
+            private_key: private_key,
+            default_tag_id: conf::get_conf_val(&conf, "github", "default_tag_id")
+                .map(|id| Uuid::parse_str(&id[..])
+                     .expect("github default_tag_id should be a uuid")),
+        }
+    });
     let github = GithubConf {
         webhook_secret: conf::get_conf_val(&conf, "github", "webhook_secret")
             .unwrap().to_owned(),
+        allow_sha1: conf::get_conf_group(&conf, "github")
+            .and_then(|g| g.get("allow_sha1").and_then(|v| v.as_bool()))
+            .unwrap_or(false),
+        app: app,
     };
+    let using_app = github.app.is_some();
     let mut repos = vec![];
     for r in conf.get("repos").and_then(|r| r.as_slice())
         .expect("Missing conf for repos!") {
@@ -74,6 +190,7 @@ pub fn load_conf(conf_filename: &str) -> AppConf {
                 .expect("repos should be a list of tables");
             let rc = RepoConf {
                 token: t.get("token").and_then(|t| t.as_str())
+                    .or(if using_app { Some("") } else { None })
                     .expect("Repo missing token").to_owned(),
                     org: t.get("org").and_then(|t| t.as_str())
                         .expect("Repo missing org").to_owned(),
@@ -83,14 +200,60 @@ pub fn load_conf(conf_filename: &str) -> AppConf {
                                 .and_then(|t| t.as_str())
                                 .and_then(|id| Uuid::parse_str(id).ok())
                                 .expect("Repo missing braid tag_id").to_owned(),
+                            announce: t.get("announce")
+                                .and_then(|a| a.as_slice())
+                                .map(|a| a.iter()
+                                     .map(|v| v.as_str()
+                                          .expect("announce should be a list of actions")
+                                          .to_owned())
+                                     .collect())
+                                .unwrap_or(default_announce()),
 
             };
             repos.push(rc);
         }
+    let mut users = vec![];
+    if let Some(us) = conf.get("users").and_then(|u| u.as_slice()) {
+        for u in us {
+            let t = u.as_table()
+                .expect("users should be a list of tables");
+            let uc = UserConf {
+                braid_id: t.get("braid_id")
+                    .and_then(|t| t.as_str())
+                    .and_then(|id| Uuid::parse_str(id).ok())
+                    .expect("User missing braid_id"),
+                github_login: t.get("github_login").and_then(|t| t.as_str())
+                    .expect("User missing github_login").to_owned(),
+            };
+            users.push(uc);
+        }
+    }
+    let oauth = conf::get_conf_group(&conf, "oauth").map(|_| {
+        conf::validate_conf_group(&conf, "oauth",
+                                  &["client_id", "client_secret",
+                                    "redirect_url", "encryption_key"]);
+        let key = conf::get_conf_val(&conf, "oauth", "encryption_key")
+            .unwrap()
+            .from_hex()
+            .expect("oauth encryption_key should be hex");
+        if key.len() != 32 {
+            panic!("oauth encryption_key should be 32 bytes");
+        }
+        OauthConf {
+            client_id: conf::get_conf_val(&conf, "oauth", "client_id").unwrap(),
+            client_secret: conf::get_conf_val(&conf, "oauth", "client_secret")
+                .unwrap(),
+            redirect_url: conf::get_conf_val(&conf, "oauth", "redirect_url")
+                .unwrap(),
+            encryption_key: key,
+        }
+    });
     AppConf {
         general: general,
         braid: braid,
         github: github,
         repos: repos,
+        users: users,
+        oauth: oauth,
     }
 }
@@ -8,6 +8,7 @@ use uuid::Uuid;
 
 use app_conf::BraidConf;
 use message;
+use metrics;
 
 pub fn send_braid_request(message: message::Message, braid_conf: &BraidConf)
 {
@@ -22,7 +23,9 @@ pub fn send_braid_request(message: message::Message, braid_conf: &BraidConf)
     headers.set(Authorization(Basic{
         username: braid_conf.app_id.clone(),
         password: Some(braid_conf.token.clone())}));
-    match client.put(&api_url[..]).body(&body[..]).headers(headers).send() {
+    match metrics::time_request("braid", || {
+        client.put(&api_url[..]).body(&body[..]).headers(headers).send()
+    }) {
         Ok(r) => {
             println!("Sent message to braid");
             if r.status == StatusCode::Created {
@@ -45,7 +48,9 @@ pub fn get_user_nick(user_id: Uuid, braid_conf: &BraidConf) -> Option<String> {
         username: braid_conf.app_id.clone(),
         password: Some(braid_conf.token.clone())}));
     let client = Client::new();
-    match client.get(&api_url[..]).headers(headers).send() {
+    match metrics::time_request("braid", || {
+        client.get(&api_url[..]).headers(headers).send()
+    }) {
         Ok(mut r) => {
             if r.status == StatusCode::Ok {
                 let mut buf = String::new();
@@ -63,8 +68,41 @@ pub fn get_user_nick(user_id: Uuid, braid_conf: &BraidConf) -> Option<String> {
     }
 }
 
+pub fn get_thread(thread_id: Uuid, braid_conf: &BraidConf) -> Option<message::Thread> {
+    let api_url = format!("{}/bots/threads/{}", braid_conf.api_url,
+                          thread_id.hyphenated().to_string());
+    let mut headers = Headers::new();
+    headers.set(Authorization(Basic {
+        username: braid_conf.app_id.clone(),
+        password: Some(braid_conf.token.clone())}));
+    let client = Client::new();
+    match metrics::time_request("braid", || {
+        client.get(&api_url[..]).headers(headers).send()
+    }) {
+        Ok(mut r) => {
+            if r.status == StatusCode::Ok {
+                let mut buf = vec![];
+                r.read_to_end(&mut buf).ok()
+                    .and_then(|_| message::decode_transit_thread(buf))
+            } else {
+                println!("Something went wrong: {:?}", r);
+                None
+            }
+        }
+        Err(e) => {
+            println!("Failed to get from braid: {:?}", e.description());
+            None
+        }
+
+    }
+}
+
 pub fn thread_url(braid_conf: &BraidConf, msg: &message::Message) -> String {
-    format!("{}/{}/thread/{}", braid_conf.site_url, msg.group_id, msg.thread_id)
+    group_thread_url(braid_conf, msg.group_id, msg.thread_id)
+}
+
+pub fn group_thread_url(braid_conf: &BraidConf, group_id: Uuid, thread_id: Uuid) -> String {
+    format!("{}/{}/thread/{}", braid_conf.site_url, group_id, thread_id)
 }
 
 pub fn start_watching_thread(thread_id: Uuid, braid_conf: &BraidConf) {
@@ -75,7 +113,9 @@ pub fn start_watching_thread(thread_id: Uuid, braid_conf: &BraidConf) {
         username: braid_conf.app_id.clone(),
         password: Some(braid_conf.token.clone())}));
     let client = Client::new();
-    match client.put(&api_url[..]).headers(headers).send() {
+    match metrics::time_request("braid", || {
+        client.put(&api_url[..]).headers(headers).send()
+    }) {
         Ok(r) => {
             println!("Sent message to braid");
             if r.status == StatusCode::Created {
@@ -1,9 +1,14 @@
+use std::collections::HashMap;
 use regex::Regex;
+use uuid::Uuid;
 
-use app_conf::{AppConf};
+use app_conf::{AppConf,RepoConf};
 use message;
 use braid;
 use github;
+use github_types::{Issue,SearchResults};
+use tracking;
+use tracking::{WatchedThread,ThreadKind};
 
 fn strip_leading_name(msg: &str) -> String {
     lazy_static! {
@@ -13,16 +18,138 @@ fn strip_leading_name(msg: &str) -> String {
 }
 
 pub fn parse_command(msg: message::Message, conf: AppConf) {
+    // We stay subscribed to threads that have been unlinked, so ignore
+    // anything that isn't actually talking to us
+    if !is_bot_command(&msg, &conf) {
+        println!("Ignoring message not addressed to us");
+        return
+    }
     let body = strip_leading_name(&msg.content[..]);
     if let Some(command) = body.split_whitespace().next() {
         match &command[..] {
             "list" => send_repos_list(msg, conf),
             "create" => create_github_issue(msg, conf),
+            "show" => show_github_issue(msg, conf),
+            "link" => link_github_issue(msg, conf),
+            "file" => file_thread_as_issue(msg, conf),
+            "search" => search_github_issues(msg, conf),
+            "mine" => send_my_issues(msg, conf),
+            "iam" => verify_github_login(msg, conf),
+            "authorize" => send_oauth_link(msg, conf),
             "help" | _ => send_help_response(msg, conf),
         }
     }
 }
 
+// Is this message addressed to us? In a tracked thread, that means it's a
+// command rather than a comment to send to github
+pub fn is_bot_command(msg: &message::Message, conf: &AppConf) -> bool {
+    let bot_command = format!("/{}", conf.braid.name);
+    msg.content.split_whitespace().next() == Some(&bot_command[..])
+}
+
+pub fn parse_thread_command(thread: WatchedThread, msg: message::Message, conf: AppConf) {
+    let body = strip_leading_name(&msg.content[..]);
+    let mut words = body.split_whitespace();
+    match words.next() {
+        Some("close") => {
+            let reason = words.collect::<Vec<_>>().join(" ");
+            set_issue_state(thread, "closed", reason, msg, conf)
+        }
+        Some("reopen") => set_issue_state(thread, "open", String::new(), msg, conf),
+        Some("unlink") => unlink_thread(thread, msg, conf),
+        Some("mute") => mute_thread(thread, true, msg, conf),
+        Some("unmute") => mute_thread(thread, false, msg, conf),
+        _ => parse_command(msg, conf),
+    }
+}
+
+fn set_issue_state(thread: WatchedThread, state: &str, reason: String,
+                   msg: message::Message, conf: AppConf)
+{
+    let braid_conf = conf.braid.clone();
+    let repo_conf = match github::find_repo_conf(&thread.repository[..], &conf) {
+        Some(c) => c,
+        None => {
+            println!("Couldn't find conf for repo {}", thread.repository);
+            return
+        }
+    };
+    let kind = match thread.kind {
+        ThreadKind::Issue => "issue",
+        ThreadKind::PullRequest => "pull request",
+    };
+    // Github sends no webhook for a change that changes nothing, so we
+    // mustn't record one or it'd swallow the next real change
+    match github::get_issue(repo_conf, thread.issue_number) {
+        Some(ref issue) if issue.state == state => {
+            let reply = format!("The {} is already {}\n{}", kind, state,
+                                issue.html_url);
+            braid::send_braid_request(message::response_to(msg, reply), &braid_conf);
+            return
+        }
+        Some(_) => {}
+        None => {
+            let reply = format!("Couldn't get the {}, sorry", kind);
+            braid::send_braid_request(message::response_to(msg, reply), &braid_conf);
+            return
+        }
+    }
+    tracking::track_state_change(thread.thread_id, state, &conf);
+    let reply = match github::set_issue_state(repo_conf, thread.issue_number, state) {
+        Some(issue) => {
+            // Only explain the close once it's actually happened
+            if !reason.is_empty() {
+                let sender = github::credit_braid_user(msg.user_id, &conf)
+                    .unwrap_or("a braid user".to_owned());
+                let comment = format!("{} closed this via [braid]({}):\n{}",
+                                      sender, braid::thread_url(&braid_conf, &msg),
+                                      reason);
+                let token = github::user_github_token(msg.user_id, &conf)
+                    .unwrap_or(repo_conf.token.clone());
+                match github::comment_on_issue(repo_conf, &token[..],
+                                               thread.issue_number, comment) {
+                    Some(c) => tracking::track_comment(thread.thread_id, c.id, &conf),
+                    None => println!("Couldn't post close reason"),
+                }
+            }
+            let action = if state == "closed" { "Closed" } else { "Reopened" };
+            format!("{} {} #{}\n{}", action, kind, issue.number, issue.html_url)
+        }
+        None => {
+            // forget about the change, so the next real one gets announced
+            tracking::did_we_change_state(thread.thread_id, state, &conf);
+            format!("Couldn't update the {}, sorry", kind)
+        }
+    };
+    braid::send_braid_request(message::response_to(msg, reply), &braid_conf);
+}
+
+fn unlink_thread(thread: WatchedThread, msg: message::Message, conf: AppConf) {
+    let reply = if tracking::remove_watched_thread(thread.thread_id, &conf) {
+        format!("This thread is no longer connected to {}#{}",
+                thread.repository, thread.issue_number)
+    } else {
+        "Couldn't unlink this thread, sorry".to_owned()
+    };
+    braid::send_braid_request(message::response_to(msg, reply), &conf.braid);
+}
+
+fn mute_thread(thread: WatchedThread, muted: bool, msg: message::Message,
+               conf: AppConf)
+{
+    let reply = if !tracking::set_thread_muted(thread.thread_id, muted, &conf) {
+        "Couldn't change muting for this thread, sorry".to_owned()
+    } else if muted {
+        format!("I'll stop posting updates from {}#{} here until you unmute",
+                thread.repository, thread.issue_number)
+    } else {
+        format!("I'll post updates from {}#{} here again",
+                thread.repository, thread.issue_number)
+    };
+    braid::send_braid_request(message::response_to(msg, reply), &conf.braid);
+}
+
 fn send_help_response(msg: message::Message, conf: AppConf) {
     let bot_name = conf.braid.name.clone();
     let mut help = String::new();
@@ -37,6 +164,42 @@ fn send_help_response(msg: message::Message, conf: AppConf) {
         format!("'/{} create <repo> <text...>' and I'll create an issue",
                 bot_name).as_str());
     help.push_str("in <repo> with the title 'text...'\n");
+    help.push_str("  Any following lines become the issue's description, and ");
+    help.push_str("'label:<name>', 'assign:@<user>' or 'milestone:<name>' ");
+    help.push_str("anywhere in the message get applied to the issue\n");
+    help.push_str(
+        format!("'/{} show <repo>#<number>' will show a summary of that issue or pull request\n",
+                bot_name).as_str());
+    help.push_str(
+        format!("'/{} link <repo>#<number>' will connect this thread to that issue or pull request\n",
+                bot_name).as_str());
+    help.push_str(
+        format!("'/{} file <repo> <text...>' will create an issue titled 'text...' quoting this thread, and connect the thread to it\n",
+                bot_name).as_str());
+    help.push_str(
+        format!("'/{} search <repo> <query...>' will find matching issues & pull requests; github qualifiers like is:open, label:bug or author:someone work too\n",
+                bot_name).as_str());
+    help.push_str(
+        format!("'/{} mine' will list the open issues & pull requests assigned to you or waiting for your review\n",
+                bot_name).as_str());
+    help.push_str(
+        format!("'/{} iam <github login>' will link your braid & github accounts\n",
+                bot_name).as_str());
+    help.push_str(
+        format!("'/{} authorize' will let me post your comments & issues on github as you\n",
+                bot_name).as_str());
+    help.push_str("In a thread for an issue or pull request:\n");
+    help.push_str(
+        format!("'/{} close [reason]' will close it, commenting the reason\n",
+                bot_name).as_str());
+    help.push_str(
+        format!("'/{} reopen' will reopen it\n", bot_name).as_str());
+    help.push_str(
+        format!("'/{} mute' and '/{} unmute' will stop and restart posting github updates\n",
+                bot_name, bot_name).as_str());
+    help.push_str(
+        format!("'/{} unlink' will disconnect the thread from github entirely\n",
+                bot_name).as_str());
 
     braid::send_braid_request(message::response_to(msg, help), &conf.braid);
 
@@ -54,22 +217,515 @@ fn send_repos_list(msg: message::Message, conf: AppConf) {
     braid::send_braid_request(msg, &conf.braid);
 }
 
-fn create_github_issue(msg: message::Message, conf: AppConf) {
+// How many lines of an issue's description to show
+static SHOW_BODY_LINES: usize = 5;
+
+fn show_github_issue(msg: message::Message, conf: AppConf) {
+    let braid_conf = conf.braid.clone();
+
+    let body = strip_leading_name(&msg.content[..]);
+    let issue_ref = body.split_whitespace().nth(1)
+        .and_then(|s| github::find_issue_ref(s, &conf));
+    let reply = match issue_ref {
+        None => "Don't know which issue you mean, sorry. Try <repo>#<number>".to_owned(),
+        Some((repo_conf, number)) => {
+            match github::get_issue(repo_conf, number) {
+                None => format!("Couldn't get {}/{}#{}, sorry",
+                                repo_conf.org, repo_conf.repo, number),
+                Some(issue) => {
+                    let kind = if issue.pull_request.is_some() {
+                        "pull request"
+                    } else {
+                        "issue"
+                    };
+                    let labels = issue.labels.iter()
+                        .map(|l| &l.name[..])
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    let assignees = issue.assignees.iter()
+                        .map(|a| &a.login[..])
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    let mut reply = format!("{} #{}: {} [{}]\n",
+                                            kind, issue.number, issue.title,
+                                            issue.state);
+                    reply.push_str(&format!("by {}, {} comments\n",
+                                            issue.user.login,
+                                            issue.comments)[..]);
+                    if !labels.is_empty() {
+                        reply.push_str(&format!("labels: {}\n", labels)[..]);
+                    }
+                    if !assignees.is_empty() {
+                        reply.push_str(&format!("assigned to: {}\n", assignees)[..]);
+                    }
+                    if let Some(body) = issue.body {
+                        let lines = body.lines().collect::<Vec<_>>();
+                        for line in lines.iter().take(SHOW_BODY_LINES) {
+                            reply.push_str("> ");
+                            reply.push_str(line);
+                            reply.push_str("\n");
+                        }
+                        if lines.len() > SHOW_BODY_LINES {
+                            reply.push_str("> ...\n");
+                        }
+                    }
+                    reply.push_str(&issue.html_url[..]);
+                    reply
+                }
+            }
+        }
+    };
+    braid::send_braid_request(message::response_to(msg, reply), &braid_conf);
+}
+
+fn link_github_issue(msg: message::Message, conf: AppConf) {
+    let braid_conf = conf.braid.clone();
+
+    if let Some(thread) = tracking::issue_for_thread(msg.thread_id, &conf) {
+        let err_resp = format!("This thread is already connected to {}#{}",
+                               thread.repository, thread.issue_number);
+        braid::send_braid_request(message::response_to(msg, err_resp), &braid_conf);
+        return
+    }
+    let body = strip_leading_name(&msg.content[..]);
+    let issue_ref = body.split_whitespace().nth(1)
+        .and_then(|s| github::find_issue_ref(s, &conf));
+    let reply = match issue_ref {
+        None => "Don't know which issue you mean, sorry. Try <repo>#<number>".to_owned(),
+        Some((repo_conf, number)) => {
+            let repo_name = format!("{}/{}", repo_conf.org, repo_conf.repo);
+            if let Some(thread) = tracking::thread_for_issue(repo_name.clone(),
+                                                             number, &conf) {
+                format!("{}#{} is already connected to {}", repo_name, number,
+                        braid::group_thread_url(&braid_conf, msg.group_id,
+                                                thread.thread_id))
+            } else {
+                match github::get_issue(repo_conf, number) {
+                    None => format!("Couldn't get {}#{}, sorry", repo_name, number),
+                    Some(issue) => {
+                        let kind = if issue.pull_request.is_some() {
+                            ThreadKind::PullRequest
+                        } else {
+                            ThreadKind::Issue
+                        };
+                        if tracking::add_watched_thread(msg.thread_id,
+                                                        repo_name.clone(),
+                                                        number, kind, &conf) {
+                            braid::start_watching_thread(msg.thread_id,
+                                                         &braid_conf);
+                            format!("This thread is now connected to \"{}\"\n{}",
+                                    issue.title, issue.html_url)
+                        } else {
+                            format!("Couldn't connect this thread to {}#{}, sorry",
+                                    repo_name, number)
+                        }
+                    }
+                }
+            }
+        }
+    };
+    braid::send_braid_request(message::response_to(msg, reply), &braid_conf);
+}
+
+// How many search results to show
+static SEARCH_RESULTS: usize = 10;
+
+fn search_github_issues(msg: message::Message, conf: AppConf) {
     let braid_conf = conf.braid.clone();
 
     let body = strip_leading_name(&msg.content[..]);
     let mut words = body.split_whitespace();
     let repo_conf = words.nth(1)
         .and_then(|s| github::find_repo_conf(s, &conf));
+    let query = words.collect::<Vec<_>>().join(" ");
+    let reply = match repo_conf {
+        None => "Don't know which repo you mean, sorry".to_owned(),
+        Some(repo_conf) => {
+            match github::search_issues(repo_conf, &query[..], SEARCH_RESULTS) {
+                None => "Couldn't search github, sorry".to_owned(),
+                Some(ref results) if results.items.is_empty() =>
+                    format!("Nothing in {}/{} matches \"{}\"",
+                            repo_conf.org, repo_conf.repo, query),
+                Some(results) => {
+                    let mut reply = format!("{} results in {}/{}",
+                                            results.total_count,
+                                            repo_conf.org, repo_conf.repo);
+                    if results.total_count as usize > results.items.len() {
+                        reply.push_str(&format!(", here are the top {}",
+                                                results.items.len())[..]);
+                    }
+                    reply.push_str("\n");
+                    for issue in results.items {
+                        reply.push_str(&format!("#{} {} [{}] {}\n",
+                                                issue.number, issue.title,
+                                                issue.state, issue.html_url)[..]);
+                    }
+                    reply
+                }
+            }
+        }
+    };
+    braid::send_braid_request(message::response_to(msg, reply), &braid_conf);
+}
+
+fn is_github_login(login: &str) -> bool {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"^[A-Za-z0-9](?:[A-Za-z0-9-]{0,38})$").unwrap();
+    }
+    RE.is_match(login)
+}
+
+// Linking accounts happens in two steps: the first time we give the user a
+// code to put in a public gist's description, the second time we check for it
+fn verify_github_login(msg: message::Message, conf: AppConf) {
+    let braid_conf = conf.braid.clone();
+
+    let body = strip_leading_name(&msg.content[..]);
+    let login = match body.split_whitespace().nth(1) {
+        Some(l) => l.trim_left_matches('@').to_owned(),
+        None => {
+            let err_resp = "Which github account are you?".to_owned();
+            braid::send_braid_request(message::response_to(msg, err_resp),
+                                      &braid_conf);
+            return
+        }
+    };
+    // The login ends up in api paths, so it had better be a login
+    if !is_github_login(&login[..]) {
+        let err_resp = format!("\"{}\" isn't a github username", login);
+        braid::send_braid_request(message::response_to(msg, err_resp), &braid_conf);
+        return
+    }
+    let pending = tracking::pending_github_verification(msg.user_id, &conf)
+        .and_then(|(l, code)| if l == login { Some(code) } else { None });
+    let reply = match pending {
+        Some(ref code) if github::has_gist_with_code(&login[..], &code[..], &conf) => {
+            if tracking::set_github_login(msg.user_id, &login[..], &conf) {
+                format!("Thanks, I'll know you as {} on github now. \
+                         You can delete the gist.", login)
+            } else {
+                "Couldn't save your github account, sorry".to_owned()
+            }
+        }
+        Some(code) => {
+            format!("I couldn't find a public gist from {} with the description \
+                     \"octocat-verify {}\". Once it's there, run this again.",
+                    login, code)
+        }
+        None => {
+            match tracking::start_github_verification(msg.user_id, &login[..], &conf) {
+                Some(code) => format!(
+                    "To prove you're {} on github, create a public gist with \
+                     the description \"octocat-verify {}\", then run \
+                     '/{} iam {}' again.",
+                    login, code, braid_conf.name, login),
+                None => "Couldn't start verifying your account, sorry".to_owned(),
+            }
+        }
+    };
+    braid::send_braid_request(message::response_to(msg, reply), &braid_conf);
+}
+
+// The link is tied to the requester's braid account, so it's sent privately
+// rather than into the thread. It only works for the github account they've
+// already proven with iam (or that's in the conf).
+fn send_oauth_link(msg: message::Message, conf: AppConf) {
+    let login = github::github_login_for(msg.user_id, &conf);
+    let reply = match (conf.oauth.as_ref(), login) {
+        (None, _) => "I'm not set up to act as github users, sorry".to_owned(),
+        (Some(_), None) => format!(
+            "Tell me which github account is yours first, with '/{} iam <login>'",
+            conf.braid.name),
+        (Some(oauth_conf), Some(login)) => {
+            match tracking::start_oauth(msg.user_id, &conf) {
+                Some(state) => {
+                    let link = format!(
+                        "Authorize me as {} on github and I'll post as you from now on: {}",
+                        login, github::oauth_authorize_url(oauth_conf, &state[..]));
+                    braid::send_braid_request(
+                        message::private_msg(msg.group_id, msg.user_id, link),
+                        &conf.braid);
+                    "I've sent you a private message with a link to authorize me".to_owned()
+                }
+                None => "Couldn't start authorizing, sorry".to_owned(),
+            }
+        }
+    };
+    braid::send_braid_request(message::response_to(msg, reply), &conf.braid);
+}
+
+fn send_my_issues(msg: message::Message, conf: AppConf) {
+    let braid_conf = conf.braid.clone();
+
+    let login = match github::github_login_for(msg.user_id, &conf) {
+        Some(l) => l,
+        None => {
+            let err_resp = "I don't know your github account, sorry".to_owned();
+            braid::send_braid_request(message::response_to(msg, err_resp),
+                                      &braid_conf);
+            return
+        }
+    };
+    // One search per kind across all the repos, rather than per repo, to
+    // stay inside github's search rate limit
+    let repos: Vec<&RepoConf> = conf.repos.iter().collect();
+    let mut failed = vec![];
+    let mut truncated = false;
+    let assigned = collect_search_results(
+        github::search_repos_issues(&repos[..], &format!("is:open assignee:{}", login)[..],
+                                    MINE_RESULTS),
+        &mut failed, &mut truncated);
+    let reviewing = collect_search_results(
+        github::search_repos_issues(&repos[..],
+                                    &format!("is:open review-requested:{}", login)[..],
+                                    MINE_RESULTS),
+        &mut failed, &mut truncated);
+    let mut reply = String::new();
+    for repo_conf in &conf.repos {
+        let repo_assigned: Vec<&Issue> = assigned.iter()
+            .filter(|i| issue_in_repo(i, repo_conf)).collect();
+        let repo_reviewing: Vec<&Issue> = reviewing.iter()
+            .filter(|i| issue_in_repo(i, repo_conf))
+            .filter(|i| !repo_assigned.iter().any(|a| a.number == i.number))
+            .collect();
+        if repo_assigned.is_empty() && repo_reviewing.is_empty() {
+            continue
+        }
+        reply.push_str(&format!("{}/{}:\n", repo_conf.org, repo_conf.repo)[..]);
+        for issue in repo_assigned {
+            reply.push_str(&format!("  #{} {} {}\n", issue.number, issue.title,
+                                    issue.html_url)[..]);
+        }
+        for issue in repo_reviewing {
+            reply.push_str(&format!("  #{} {} (review requested) {}\n",
+                                    issue.number, issue.title,
+                                    issue.html_url)[..]);
+        }
+    }
+    if reply.is_empty() && failed.is_empty() {
+        reply = format!("Nothing open is waiting on {}", login);
+    }
+    if truncated {
+        reply.push_str(&format!("There's more than I can show here, only the first {} \
+                                 of each kind are listed\n", MINE_RESULTS)[..]);
+    }
+    if !failed.is_empty() {
+        reply.push_str(&format!("Couldn't search {}, sorry (github may be rate-limiting us)",
+                                failed.join(", "))[..]);
+    }
+    braid::send_braid_request(message::response_to(msg, reply), &braid_conf);
+}
+
+// How many of each kind of issue to get for the mine command
+static MINE_RESULTS: usize = 100;
+
+// Gather the issues from searching several repos, noting which repos'
+// searches failed and whether any had more results than we got
+fn collect_search_results(batches: Vec<(Vec<&RepoConf>, Option<SearchResults>)>,
+                          failed: &mut Vec<String>, truncated: &mut bool) -> Vec<Issue>
+{
+    let mut items = vec![];
+    for (repos, results) in batches {
+        match results {
+            Some(results) => {
+                if results.total_count as usize > results.items.len() {
+                    *truncated = true;
+                }
+                items.extend(results.items);
+            }
+            None => {
+                for repo_conf in repos {
+                    let repo_name = format!("{}/{}", repo_conf.org, repo_conf.repo);
+                    if !failed.contains(&repo_name) {
+                        failed.push(repo_name);
+                    }
+                }
+            }
+        }
+    }
+    items
+}
+
+fn issue_in_repo(issue: &Issue, repo_conf: &RepoConf) -> bool {
+    let repo_path = format!("/repos/{}/{}", repo_conf.org, repo_conf.repo);
+    issue.repository_url.to_lowercase().ends_with(&repo_path.to_lowercase()[..])
+}
+
+// Quote a braid thread's messages for an issue description
+fn quote_thread(thread: message::Thread, skip: Uuid, conf: &AppConf) -> String {
+    let mut nicks = HashMap::new();
+    let mut quoted = String::new();
+    for m in thread.messages.into_iter().filter(|m| m.id != skip) {
+        let nick = nicks.entry(m.user_id)
+            .or_insert_with(|| braid::get_user_nick(m.user_id, &conf.braid)
+                            .unwrap_or("a braid user".to_owned()))
+            .clone();
+        quoted.push_str(&format!("> **{}**:\n", nick)[..]);
+        for line in m.content.lines() {
+            quoted.push_str("> ");
+            quoted.push_str(line);
+            quoted.push_str("\n");
+        }
+        quoted.push_str("\n");
+    }
+    quoted
+}
+
+fn file_thread_as_issue(msg: message::Message, conf: AppConf) {
+    let braid_conf = conf.braid.clone();
+
+    // A thread can only be connected to one issue; check before filing a new
+    // one that we'd fail to connect
+    if let Some(thread) = tracking::issue_for_thread(msg.thread_id, &conf) {
+        let err_resp = format!("This thread is already connected to {}#{}",
+                               thread.repository, thread.issue_number);
+        braid::send_braid_request(message::response_to(msg, err_resp), &braid_conf);
+        return
+    }
+    let body = strip_leading_name(&msg.content[..]);
+    let mut words = body.split_whitespace();
+    let repo_conf = match words.nth(1).and_then(|s| github::find_repo_conf(s, &conf)) {
+        Some(r) => r,
+        None => {
+            let err_resp = "Don't know which repo you mean, sorry".to_owned();
+            braid::send_braid_request(message::response_to(msg, err_resp),
+                                      &braid_conf);
+            return
+        }
+    };
     let issue_title = words.collect::<Vec<_>>().join(" ");
-    if let Some(repo_conf) = repo_conf {
-        let sender = braid::get_user_nick(msg.user_id, &braid_conf)
+    let thread = match braid::get_thread(msg.thread_id, &braid_conf) {
+        Some(t) => t,
+        None => {
+            let err_resp = "Couldn't read this thread, sorry".to_owned();
+            braid::send_braid_request(message::response_to(msg, err_resp),
+                                      &braid_conf);
+            return
+        }
+    };
+    let user_token = github::user_github_token(msg.user_id, &conf);
+    let mut content = quote_thread(thread, msg.id, &conf);
+    if user_token.is_some() {
+        content.push_str(&format!("Filed from [braid chat]({})",
+                                  braid::thread_url(&braid_conf, &msg))[..]);
+    } else {
+        let sender = github::credit_braid_user(msg.user_id, &conf)
             .unwrap_or("a braid user".to_owned());
-        let content = format!(
-            "Created by octocat bot on behalf of {} from [braid chat]({})",
+        content.push_str(&format!(
+            "Filed by octocat bot on behalf of {} from [braid chat]({})",
             sender,
-            braid::thread_url(&braid_conf, &msg));
-        let gh_resp = github::create_issue(repo_conf, issue_title, content);
+            braid::thread_url(&braid_conf, &msg))[..]);
+    }
+    let token = user_token.unwrap_or(repo_conf.token.clone());
+    let new_issue = github::NewIssue {
+        title: issue_title,
+        body: content,
+        labels: vec![],
+        assignees: vec![],
+        milestone: None,
+    };
+    let reply = match github::create_issue(repo_conf, &token[..], new_issue) {
+        Some(issue) => {
+            // Track the thread straight away, so the opened webhook doesn't
+            // start a new one
+            let repo_name = format!("{}/{}", repo_conf.org, repo_conf.repo);
+            if tracking::add_watched_thread(msg.thread_id, repo_name.clone(),
+                                            issue.number, ThreadKind::Issue,
+                                            &conf) {
+                braid::start_watching_thread(msg.thread_id, &braid_conf);
+                format!("Filed issue #{}, this thread is now connected to it\n{}",
+                        issue.number, issue.html_url)
+            } else {
+                // the webhook beat us to it
+                match tracking::thread_for_issue(repo_name, issue.number, &conf) {
+                    Some(thread) => format!(
+                        "Filed issue #{}, but it's connected to {} instead\n{}",
+                        issue.number,
+                        braid::group_thread_url(&braid_conf, msg.group_id,
+                                                thread.thread_id),
+                        issue.html_url),
+                    None => format!(
+                        "Filed issue #{}, but couldn't connect this thread to it, sorry\n{}",
+                        issue.number, issue.html_url),
+                }
+            }
+        }
+        None => {
+            println!("Couldn't create issue");
+            "Couldn't create issue, sorry".to_owned()
+        }
+    };
+    braid::send_braid_request(message::response_to(msg, reply), &braid_conf);
+}
+
+// Pull the label:x, assign:@x & milestone:x tokens out of an issue's text
+fn extract_issue_tokens(text: &str) -> (String, Vec<(String, String)>) {
+    lazy_static! {
+        static ref RE: Regex =
+            Regex::new(r"(?m)(?:^|[ \t])(label|assign|milestone):@?(\S+)").unwrap();
+    }
+    let tokens = RE.captures_iter(text)
+        .map(|cap| (cap.at(1).unwrap().to_owned(), cap.at(2).unwrap().to_owned()))
+        .collect();
+    (RE.replace_all(text, ""), tokens)
+}
+
+fn create_github_issue(msg: message::Message, conf: AppConf) {
+    let braid_conf = conf.braid.clone();
+
+    let body = strip_leading_name(&msg.content[..]);
+    let (body, tokens) = extract_issue_tokens(&body[..]);
+    let mut lines = body.lines();
+    let mut words = lines.next().unwrap_or("").split_whitespace();
+    let repo_conf = words.nth(1)
+        .and_then(|s| github::find_repo_conf(s, &conf));
+    let issue_title = words.collect::<Vec<_>>().join(" ");
+    let issue_body = lines.collect::<Vec<_>>().join("\n");
+    if let Some(repo_conf) = repo_conf {
+        let mut labels = vec![];
+        let mut assignees = vec![];
+        let mut milestone = None;
+        for (kind, value) in tokens {
+            match &kind[..] {
+                "label" => labels.push(value),
+                "assign" => assignees.push(value),
+                _ => {
+                    milestone = github::find_milestone(repo_conf, &value[..]);
+                    if milestone.is_none() {
+                        let err_resp = format!("Couldn't find milestone {}, sorry",
+                                               value);
+                        braid::send_braid_request(
+                            message::response_to(msg, err_resp), &braid_conf);
+                        return
+                    }
+                }
+            }
+        }
+        let user_token = github::user_github_token(msg.user_id, &conf);
+        let mut content = issue_body.trim().to_owned();
+        if !content.is_empty() {
+            content.push_str("\n\n");
+        }
+        if user_token.is_some() {
+            content.push_str(&format!("Created from [braid chat]({})",
+                                      braid::thread_url(&braid_conf, &msg))[..]);
+        } else {
+            let sender = github::credit_braid_user(msg.user_id, &conf)
+                .unwrap_or("a braid user".to_owned());
+            content.push_str(&format!(
+                "Created by octocat bot on behalf of {} from [braid chat]({})",
+                sender,
+                braid::thread_url(&braid_conf, &msg))[..]);
+        }
+        let token = user_token.unwrap_or(repo_conf.token.clone());
+        let new_issue = github::NewIssue {
+            title: issue_title,
+            body: content,
+            labels: labels,
+            assignees: assignees,
+            milestone: milestone,
+        };
+        let gh_resp = github::create_issue(repo_conf, &token[..], new_issue);
         if let Some(gh_issue) = gh_resp {
             // Opened webhook from github will open thread on braid
             println!("Issue opened: {:?}", gh_issue);
@@ -1,31 +1,52 @@
 use std::io::Read;
-use std::collections::BTreeMap;
+use regex::Regex;
 use hyper::client::{Client,Response};
-use hyper::header::{Headers,ContentType,Authorization,Bearer,UserAgent};
+use hyper::header::{Headers,ContentType,Authorization,Bearer,UserAgent,Accept,
+                    qitem};
 use hyper::error::Result as HttpResult;
+use hyper::method::Method;
+use mime::{Mime,TopLevel,SubLevel};
+use serde::Deserialize;
 use serde_json;
 use serde_json::value::{Value as JsonValue,Map};
+use uuid::Uuid;
 
-use app_conf::{AppConf,RepoConf};
+use app_conf::{AppConf,RepoConf,OauthConf};
+use github_types::{User,Issue,Comment,Milestone,SearchResults,Gist,
+                   AccessToken,IssuesEvent,IssueCommentEvent,
+                   PullRequestEvent,PullRequestReviewEvent,
+                   PullRequestReviewCommentEvent,PingEvent};
+use github_app;
 use tracking;
+use tracking::ThreadKind;
 use braid;
 use message;
+use metrics;
 
 static GITHUB_API_URL: &'static str = "https://api.github.com";
 
-fn send_github_request(token: &str, endpoint: &str, data: JsonValue) -> HttpResult<Response> {
+pub fn send_github_request(method: Method, token: &str, endpoint: &str,
+                           data: Option<JsonValue>) -> HttpResult<Response>
+{
     let mut url_str = String::from(GITHUB_API_URL);
     url_str.push_str(endpoint);
     let mut headers = Headers::new();
     headers.set(ContentType::json());
-    headers.set(Authorization(Bearer { token: token.to_owned() }));
+    // an empty token makes an unauthenticated request
+    if !token.is_empty() {
+        headers.set(Authorization(Bearer { token: token.to_owned() }));
+    }
     headers.set(UserAgent("braidchat/octocat".to_owned()));
-    let body = serde_json::to_string(&data).expect("Can't serialize data");
+    let body = data.map(|d| serde_json::to_string(&d).expect("Can't serialize data"));
     let client = Client::new();
-    client.post(url_str.as_str())
-        .body(&body[..])
-        .headers(headers)
-        .send()
+    metrics::time_request("github", || {
+        let mut req = client.request(method, url_str.as_str())
+            .headers(headers);
+        if let Some(ref b) = body {
+            req = req.body(&b[..]);
+        }
+        req.send()
+    })
 }
 
 pub fn find_repo_conf<'a>(name: &str, conf: &'a AppConf) -> Option<&'a RepoConf> {
@@ -49,16 +70,114 @@ pub fn find_repo_conf<'a>(name: &str, conf: &'a AppConf) -> Option<&'a RepoConf>
     }
 }
 
-#[derive(Debug)]
-pub struct GithubIssue {
-    pub url: String,
-    pub number: i64,
+// The github account of a braid user, if we know it, either from the config
+// or because they've verified it with the iam command
+pub fn github_login_for(user_id: Uuid, conf: &AppConf) -> Option<String> {
+    conf.users.iter()
+        .find(|u| u.braid_id == user_id)
+        .map(|u| u.github_login.clone())
+        .or_else(|| tracking::github_login_for_user(user_id, conf))
+}
+
+// The braid user for a github account, the reverse of github_login_for
+pub fn braid_user_for(login: &str, conf: &AppConf) -> Option<Uuid> {
+    conf.users.iter()
+        .find(|u| u.github_login.to_lowercase() == login.to_lowercase())
+        .map(|u| u.braid_id)
+        .or_else(|| tracking::braid_user_for_login(login, conf))
+}
+
+// The braid users for any github accounts @-mentioned in some text
+fn braid_mentions(text: &str, conf: &AppConf) -> Vec<Uuid> {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"(?:^|[^\w])@([A-Za-z0-9-]+)").unwrap();
+    }
+    let mut mentions = vec![];
+    for cap in RE.captures_iter(text) {
+        if let Some(user_id) = cap.at(1).and_then(|login| braid_user_for(login, conf)) {
+            if !mentions.contains(&user_id) {
+                mentions.push(user_id);
+            }
+        }
+    }
+    mentions
+}
+
+// How to credit a braid user on github: @-mentioning their github account if
+// we know it, otherwise by their braid nick
+pub fn credit_braid_user(user_id: Uuid, conf: &AppConf) -> Option<String> {
+    github_login_for(user_id, conf)
+        .map(|login| format!("@{}", login))
+        .or_else(|| braid::get_user_nick(user_id, &conf.braid))
+}
+
+// Does the github user have a public gist with the code in its description?
+pub fn has_gist_with_code(login: &str, code: &str, conf: &AppConf) -> bool {
+    // Public gists don't need a token, but using one gets a higher rate limit.
+    // Repos can be without one in app mode, so find any that has one.
+    let token = conf.repos.iter()
+        .map(|r| r.token.clone())
+        .find(|t| !t.is_empty())
+        .unwrap_or(String::new());
+    let path = format!("/users/{}/gists", login);
+    let gists: Option<Vec<Gist>> = parse_github_response(
+        send_github_request(Method::Get, &token[..], &path[..], None));
+    gists.map(|gs| gs.iter().any(|g| {
+        g.description.as_ref().map(|d| d.contains(code)).unwrap_or(false)
+    })).unwrap_or(false)
 }
 
-pub fn create_issue(github_conf: &RepoConf, title: String, content: String)
-    -> Option<GithubIssue>
+// Parse a reference like "repo#12" or "org/repo#12"
+pub fn find_issue_ref<'a>(issue_ref: &str, conf: &'a AppConf)
+    -> Option<(&'a RepoConf, i64)>
+{
+    let mut split = issue_ref.splitn(2, '#');
+    let repo = split.next().and_then(|r| find_repo_conf(r, conf));
+    let number = split.next().and_then(|n| n.parse::<i64>().ok());
+    match (repo, number) {
+        (Some(r), Some(n)) => Some((r, n)),
+        _ => None,
+    }
+}
+
+// Read & parse the JSON body of a response from the github API
+pub fn parse_github_response<T: Deserialize>(resp: HttpResult<Response>) -> Option<T> {
+    match resp {
+        Err(e) => { println!("Error sending github request: {:?}", e); None }
+        Ok(mut resp) => {
+            let mut buf = String::new();
+            match resp.read_to_string(&mut buf) {
+                Err(e) => { println!("Error reading github response: {:?}", e); None }
+                Ok(_) => {
+                    match serde_json::from_str(&buf[..]) {
+                        Ok(v) => Some(v),
+                        Err(e) => {
+                            println!("Couldn't parse json from github ({}): {}",
+                                     resp.status, e);
+                            None
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub struct NewIssue {
+    pub title: String,
+    pub body: String,
+    pub labels: Vec<String>,
+    pub assignees: Vec<String>,
+    pub milestone: Option<i64>,
+}
+
+fn json_strings(strs: Vec<String>) -> JsonValue {
+    JsonValue::Array(strs.into_iter().map(JsonValue::String).collect())
+}
+
+pub fn create_issue(github_conf: &RepoConf, token: &str, new_issue: NewIssue)
+    -> Option<Issue>
 {
-    let token = github_conf.token.clone();
     let owner = github_conf.org.clone();
     let repo = github_conf.repo.clone();
     let mut path = String::from("/repos/");
@@ -68,55 +187,182 @@ pub fn create_issue(github_conf: &RepoConf, title: String, content: String)
     path.push_str("/issues");
 
     let mut map = Map::new();
-    map.insert(String::from("title"), JsonValue::String(title));
-    map.insert(String::from("body"), JsonValue::String(content));
+    map.insert(String::from("title"), JsonValue::String(new_issue.title));
+    map.insert(String::from("body"), JsonValue::String(new_issue.body));
+    if !new_issue.labels.is_empty() {
+        map.insert(String::from("labels"), json_strings(new_issue.labels));
+    }
+    if !new_issue.assignees.is_empty() {
+        map.insert(String::from("assignees"), json_strings(new_issue.assignees));
+    }
+    if let Some(milestone) = new_issue.milestone {
+        map.insert(String::from("milestone"), JsonValue::I64(milestone));
+    }
     let data = JsonValue::Object(map);
 
-    match send_github_request(&token[..], path.as_str(), data) {
-        Err(e) => { println!("Error fetching from github: {:?}", e); None }
-        Ok(mut resp) => {
-            let mut buf = String::new();
-            match resp.read_to_string(&mut buf) {
-                Err(_) => { println!("Couldn't read response"); None },
-                Ok(_) => {
-                    match serde_json::from_str(&buf[..]) {
-                        Ok(new_issue) => {
-                            let new_issue: BTreeMap<String, JsonValue> = new_issue;
-                            let url = new_issue.get("html_url")
-                                .and_then(|url| url.as_string() );
-                            let number: Option<i64> = new_issue.get("number")
-                                .and_then(|n| { n.as_i64() });
-                            if let (Some(u), Some(n)) = (url, number) {
-                                Some(GithubIssue { url: u.to_owned(), number: n })
-                            } else {
-                                None
-                            }
-                        }
-                        Err(e) => { println!("Failed to parse json: {:?}", e); None }
-                    }
-                }
-            }
+    parse_github_response(send_github_request(Method::Post, token,
+                                              path.as_str(), Some(data)))
+}
+
+// Find the number of an open milestone, given its title or number
+pub fn find_milestone(repo_conf: &RepoConf, name: &str) -> Option<i64> {
+    if let Ok(number) = name.parse::<i64>() {
+        return Some(number)
+    }
+    let path = format!("/repos/{}/{}/milestones", repo_conf.org, repo_conf.repo);
+    let milestones: Option<Vec<Milestone>> = parse_github_response(
+        send_github_request(Method::Get, &repo_conf.token[..], &path[..], None));
+    milestones.and_then(|ms| ms.into_iter()
+                        .find(|m| m.title == name)
+                        .map(|m| m.number))
+}
+
+// Fetch an issue or pull request
+pub fn get_issue(repo_conf: &RepoConf, issue_number: i64) -> Option<Issue> {
+    let path = format!("/repos/{}/{}/issues/{}", repo_conf.org, repo_conf.repo,
+                       issue_number);
+    parse_github_response(send_github_request(Method::Get, &repo_conf.token[..],
+                                              &path[..], None))
+}
+
+// Percent-encode a string for use in a query parameter
+fn url_encode(s: &str) -> String {
+    let mut encoded = String::new();
+    for b in s.bytes() {
+        match b {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'_' | b'.' | b'~' =>
+                encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{:02X}", b)[..]),
         }
     }
+    encoded
 }
 
-fn new_issue_from_webhook(issue_number: i64, payload: JsonValue, conf: AppConf)
+// Search a repo's issues & pull requests. The query can use any of github's
+// search qualifiers, like is:open or label:bug
+pub fn search_issues(repo_conf: &RepoConf, query: &str, count: usize)
+    -> Option<SearchResults>
 {
-    let repo_name = match payload.find_path(&["repository", "full_name"])
-        .and_then(|n| n.as_string()) {
-            Some(r) => r,
-            None => {
-                println!("Couldn't get repository from message");
-                return
+    let q = format!("repo:{}/{} {}", repo_conf.org, repo_conf.repo, query);
+    let path = format!("/search/issues?q={}&per_page={}", url_encode(&q[..]),
+                       count);
+    parse_github_response(send_github_request(Method::Get, &repo_conf.token[..],
+                                              &path[..], None))
+}
+
+// Github rejects search queries longer than this
+static MAX_SEARCH_QUERY: usize = 256;
+
+// Search several repos' issues with as few requests as we can. Repos are
+// batched by token, since an installation token only sees its own repos, and
+// so each query stays under github's length limit. The results for a batch are
+// None if its search failed.
+pub fn search_repos_issues<'a>(repos: &[&'a RepoConf], query: &str, count: usize)
+    -> Vec<(Vec<&'a RepoConf>, Option<SearchResults>)>
+{
+    let mut batches: Vec<(Vec<&'a RepoConf>, String)> = vec![];
+    for repo_conf in repos {
+        let qualifier = format!(" repo:{}/{}", repo_conf.org, repo_conf.repo);
+        let fits = batches.iter().position(|&(ref rs, ref q)| {
+            rs[0].token == repo_conf.token
+                && q.len() + qualifier.len() <= MAX_SEARCH_QUERY
+        });
+        match fits {
+            Some(i) => {
+                batches[i].0.push(*repo_conf);
+                batches[i].1.push_str(&qualifier[..]);
             }
-        };
-    if tracking::thread_for_issue(repo_name.to_owned(), issue_number, &conf)
-        .is_some()
-        {
-            println!("Already tracking this issue");
-            return
+            None => batches.push((vec![*repo_conf], format!("{}{}", query, qualifier))),
         }
-    let repo_conf = match find_repo_conf(repo_name, &conf) {
+    }
+    batches.into_iter().map(|(rs, q)| {
+        let path = format!("/search/issues?q={}&per_page={}", url_encode(&q[..]),
+                           count);
+        let results = parse_github_response(
+            send_github_request(Method::Get, &rs[0].token[..], &path[..], None));
+        (rs, results)
+    }).collect()
+}
+
+pub fn comment_on_issue(repo_conf: &RepoConf, token: &str, issue_number: i64,
+                        body: String) -> Option<Comment>
+{
+    let path = format!("/repos/{}/{}/issues/{}/comments", repo_conf.org,
+                       repo_conf.repo, issue_number);
+    let mut map = Map::new();
+    map.insert(String::from("body"), JsonValue::String(body));
+    let data = JsonValue::Object(map);
+    parse_github_response(send_github_request(Method::Post, token,
+                                              &path[..], Some(data)))
+}
+
+static GITHUB_OAUTH_URL: &'static str = "https://github.com/login/oauth";
+
+// Where to send a braid user to let us act on github as them
+pub fn oauth_authorize_url(oauth_conf: &OauthConf, state: &str) -> String {
+    format!("{}/authorize?client_id={}&redirect_uri={}&scope=repo&state={}",
+            GITHUB_OAUTH_URL, url_encode(&oauth_conf.client_id[..]),
+            url_encode(&oauth_conf.redirect_url[..]), url_encode(state))
+}
+
+// Trade the code from the oauth callback for the user's access token
+pub fn exchange_oauth_code(oauth_conf: &OauthConf, code: &str) -> Option<String> {
+    let url_str = format!("{}/access_token", GITHUB_OAUTH_URL);
+    let mut headers = Headers::new();
+    headers.set(ContentType::json());
+    headers.set(Accept(vec![qitem(Mime(TopLevel::Application, SubLevel::Json,
+                                       vec![]))]));
+    headers.set(UserAgent("braidchat/octocat".to_owned()));
+    let mut map = Map::new();
+    map.insert(String::from("client_id"),
+               JsonValue::String(oauth_conf.client_id.clone()));
+    map.insert(String::from("client_secret"),
+               JsonValue::String(oauth_conf.client_secret.clone()));
+    map.insert(String::from("redirect_uri"),
+               JsonValue::String(oauth_conf.redirect_url.clone()));
+    map.insert(String::from("code"), JsonValue::String(code.to_owned()));
+    let body = serde_json::to_string(&JsonValue::Object(map))
+        .expect("Can't serialize data");
+    let client = Client::new();
+    let resp = metrics::time_request("github", || {
+        client.post(url_str.as_str())
+            .body(&body[..])
+            .headers(headers)
+            .send()
+    });
+    parse_github_response::<AccessToken>(resp).map(|t| t.access_token)
+}
+
+// The github user a token belongs to
+pub fn token_user(token: &str) -> Option<User> {
+    parse_github_response(send_github_request(Method::Get, token, "/user", None))
+}
+
+// A braid user's own github token, if they've authorized us through oauth
+pub fn user_github_token(user_id: Uuid, conf: &AppConf) -> Option<String> {
+    conf.oauth.as_ref()
+        .and_then(|oauth| tracking::github_token_for_user(user_id, oauth, conf))
+}
+
+// Close or reopen an issue (or pull request); state is "open" or "closed"
+pub fn set_issue_state(repo_conf: &RepoConf, issue_number: i64, state: &str)
+    -> Option<Issue>
+{
+    let path = format!("/repos/{}/{}/issues/{}", repo_conf.org, repo_conf.repo,
+                       issue_number);
+    let mut map = Map::new();
+    map.insert(String::from("state"), JsonValue::String(state.to_owned()));
+    let data = JsonValue::Object(map);
+    parse_github_response(send_github_request(Method::Patch, &repo_conf.token[..],
+                                              &path[..], Some(data)))
+}
+
+// Open a new braid thread in the repo's tag and start syncing it with the
+// given issue or pull request
+fn start_thread(repo_name: String, issue_number: i64, kind: ThreadKind,
+                content: String, conf: AppConf)
+{
+    let repo_conf = match find_repo_conf(&repo_name[..], &conf) {
         Some(c) => c,
         None => {
             println!("Couldn't find conf for {}", repo_name);
@@ -124,133 +370,457 @@ fn new_issue_from_webhook(issue_number: i64, payload: JsonValue, conf: AppConf)
         }
     };
 
-    let issue = match payload.find("issue") {
-        Some(i) => i,
-        None => { println!("No issue in payload!"); return }
-    };
-
-    let creator = match issue.find_path(&["user", "login"])
-        .and_then(|u| u.as_string()) {
-            Some(u) => u,
-            None => { println!("Missing creator name"); return }
-        };
-    let issue_title = match issue.find("title")
-        .and_then(|t| t.as_string()) {
-            Some(t) => t,
-            None => { println!("Missing issue title"); return }
-        };
-    let issue_url = match issue.find("html_url")
-        .and_then(|u| u.as_string()) {
-            Some(u) => u,
-            None => { println!("Missing issue url"); return }
-        };
-    let content = format!("{} opened issue \"{}\"\n{}",
-                          creator, issue_title, issue_url);
-
     let braid_response_tag_id = repo_conf.tag_id;
     let msg = message::new_thread_msg(braid_response_tag_id, content);
     let braid_conf = conf.braid.clone();
-    tracking::add_watched_thread(msg.thread_id, repo_name.to_owned(),
-                                 issue_number, &conf);
+    tracking::add_watched_thread(msg.thread_id, repo_name.clone(),
+                                 issue_number, kind, &conf);
     braid::send_braid_request(msg.clone(), &braid_conf);
     braid::start_watching_thread(msg.thread_id, &braid_conf);
 }
 
-fn comment_from_webhook(issue_number: i64, repo_name: &str, update: JsonValue, conf: AppConf) {
+fn new_issue_from_webhook(payload: IssuesEvent, conf: AppConf)
+{
+    let repo_name = payload.repository.full_name;
+    let issue = payload.issue;
+    if tracking::thread_for_issue(repo_name.clone(), issue.number, &conf)
+        .is_some()
+        {
+            println!("Already tracking this issue");
+            return
+        }
+
+    let content = format!("{} opened issue \"{}\"\n{}",
+                          issue.user.login, issue.title, issue.html_url);
+    start_thread(repo_name, issue.number, ThreadKind::Issue, content, conf);
+}
+
+fn comment_from_webhook(payload: IssueCommentEvent, conf: AppConf) {
+    let issue_number = payload.issue.number;
     println!("Update to issue {:?}", issue_number);
-    let thread_id = match tracking::thread_for_issue(repo_name.to_owned(),
+    let thread_id = match tracking::thread_for_issue(payload.repository.full_name,
                                                      issue_number,
                                                      &conf)
     {
+        Some(ref thread) if thread.muted => {
+            println!("Thread for this issue is muted");
+            return
+        }
         Some(thread) => thread.thread_id,
         None => {
             println!("Not tracking this issue though");
             return
         }
     };
-    let comment = match update.find("comment") {
-        Some(comment) => comment,
-        None => { println!("No comment in issue!"); return }
-    };
-    let comment_id = match comment.find("id")
-        .and_then(|i| i.as_i64()) {
-        Some(i) => i,
-        None => { println!("Missing comment id!"); return }
-    };
-    if tracking::did_we_post_comment(thread_id, comment_id, &conf) {
+    let comment = payload.comment;
+    if tracking::did_we_post_comment(thread_id, comment.id, &conf) {
         println!("webhook for our own comment");
         return
     }
-    let commenter = match comment.find_path(&["user", "login"])
-        .and_then(|u| u.as_string()) {
-            Some(c) => c,
-            None => { println!("Missing commenter"); return }
-        };
-    let comment_body = match comment.find("body")
-        .and_then(|b| b.as_string()) {
-            Some(b) => b,
-            None => { println!("Missing comment body"); return }
-        };
-    let msg_body = format!("{} commented:\n{}", commenter, comment_body);
-    let msg = message::reply_to_thread(thread_id, msg_body);
+    let msg_body = format!("{} commented:\n{}", comment.user.login, comment.body);
+    let mentions = braid_mentions(&comment.body[..], &conf);
+    let msg = message::reply_to_thread(thread_id, msg_body, mentions);
+    tracking::track_comment_message(comment.id, thread_id, msg.id, &conf);
     braid::send_braid_request(msg, &conf.braid);
 }
 
-fn closed_issue_from_webhook(issue_number: i64, repo_name: &str, update: JsonValue, conf: AppConf) {
-    println!("Issue {} in {} closed", issue_number, repo_name);
-    let thread_id = match tracking::thread_for_issue(repo_name.to_owned(),
-                                                     issue_number, &conf)
+// Braid has no way for bots to change a message after the fact, so edits &
+// deletions get posted as a new message. We only do this for comments that
+// made it into the thread in the first place.
+fn changed_comment_from_webhook(payload: IssueCommentEvent, conf: AppConf) {
+    let comment = payload.comment;
+    let thread_id = match tracking::message_for_comment(comment.id, &conf)
+        .and(tracking::thread_for_issue(payload.repository.full_name,
+                                        payload.issue.number, &conf))
     {
+        Some(ref thread) if thread.muted => {
+            println!("Thread for this issue is muted");
+            return
+        }
         Some(thread) => thread.thread_id,
         None => {
-            println!("Not tracking this issue though");
+            println!("Comment {} isn't in a thread", comment.id);
             return
         }
     };
-    let closer = update.find_path(&["sender", "login"])
-        .and_then(|u| u.as_string())
-        .unwrap_or("an unknown user");
-    let msg_body = format!("issue has been closed by {}", closer);
-    let msg = message::reply_to_thread(thread_id, msg_body);
+    // Only ping people newly mentioned by an edit, not everyone again for
+    // each typo fix
+    let (msg_body, mentions) = if payload.action == "deleted" {
+        tracking::forget_comment_message(comment.id, &conf);
+        (format!("[comment by {} was deleted by {}]", comment.user.login,
+                 payload.sender.login),
+         vec![])
+    } else {
+        let old_mentions = payload.changes.as_ref()
+            .and_then(|c| c.body.as_ref())
+            .map(|b| braid_mentions(&b.from[..], &conf))
+            .unwrap_or(vec![]);
+        let mentions = braid_mentions(&comment.body[..], &conf).into_iter()
+            .filter(|m| !old_mentions.contains(m))
+            .collect::<Vec<_>>();
+        (format!("{} edited their comment:\n{}", comment.user.login,
+                 comment.body),
+         mentions)
+    };
+    let msg = message::reply_to_thread(thread_id, msg_body, mentions);
     braid::send_braid_request(msg, &conf.braid);
 }
 
-pub fn update_from_github(msg_body: Vec<u8>, conf: AppConf) {
-    match serde_json::from_slice(&msg_body[..]) {
-        Err(e) => println!("Couldn't parse update json: {:?}", e),
-        Ok(update) => {
-            let update: JsonValue = update;
-            let repo_name = match update.find_path(&["repository", "full_name"])
-                .and_then(|n| n.as_string()) {
-                    Some(r) => r,
-                    None => {
-                        println!("Couldn't get repository from message");
-                        return
-                    }
-                };
-            let issue_number = match update.find_path(&["issue", "number"])
-                .and_then(|n| n.as_i64()) {
-                    Some(i) => i,
-                    None => { println!("Couldn't get issue #"); return }
-                };
-            let action = match update.find("action")
-                .and_then(|a| a.as_string()) {
-                    Some(a) => a,
-                    None => { println!("Couldn't get issue action!"); return }
-                };
-            match action {
-                "opened" => new_issue_from_webhook(issue_number, update.clone(), conf),
-                "created" => comment_from_webhook(issue_number, repo_name, update.clone(), conf),
-                "closed" => closed_issue_from_webhook(issue_number, repo_name, update.clone(), conf),
-                _ => println!("Unknown action from webhook {}", action),
+// Post an update to the braid thread tracking the given issue or pull
+// request. Returns false if we aren't tracking it.
+fn post_to_thread(repo_name: String, issue_number: i64, content: String,
+                  conf: &AppConf) -> bool
+{
+    match tracking::thread_for_issue(repo_name, issue_number, conf) {
+        Some(ref thread) if thread.muted => {
+            println!("Thread for this issue is muted");
+            true
+        }
+        Some(thread) => {
+            let mentions = braid_mentions(&content[..], conf);
+            let msg = message::reply_to_thread(thread.thread_id, content,
+                                               mentions);
+            braid::send_braid_request(msg, &conf.braid);
+            true
+        }
+        None => {
+            println!("Not tracking this issue though");
+            false
+        }
+    }
+}
+
+// True if a state change was made by a command from the braid thread,
+// which will have already posted a confirmation there
+fn is_our_state_change(repo_name: &str, issue_number: i64, state: &str,
+                       conf: &AppConf) -> bool
+{
+    let ours = tracking::thread_for_issue(repo_name.to_owned(), issue_number, conf)
+        .map(|thread| tracking::did_we_change_state(thread.thread_id, state, conf))
+        .unwrap_or(false);
+    if ours {
+        println!("webhook for our own state change");
+    }
+    ours
+}
+
+fn closed_issue_from_webhook(payload: IssuesEvent, conf: AppConf) {
+    let repo_name = payload.repository.full_name;
+    let issue_number = payload.issue.number;
+    println!("Issue {} in {} closed", issue_number, repo_name);
+    if is_our_state_change(&repo_name[..], issue_number, "closed", &conf) {
+        return
+    }
+    let msg_body = format!("issue has been closed by {}", payload.sender.login);
+    post_to_thread(repo_name, issue_number, msg_body, &conf);
+}
+
+fn reopened_issue_from_webhook(payload: IssuesEvent, conf: AppConf) {
+    let repo_name = payload.repository.full_name;
+    let issue = payload.issue;
+    println!("Issue {} in {} reopened", issue.number, repo_name);
+    if is_our_state_change(&repo_name[..], issue.number, "open", &conf) {
+        return
+    }
+    let msg_body = format!("issue reopened by {}", payload.sender.login);
+    if !post_to_thread(repo_name.clone(), issue.number, msg_body, &conf) {
+        let content = format!("{} reopened issue \"{}\"\n{}",
+                              payload.sender.login, issue.title,
+                              issue.html_url);
+        start_thread(repo_name, issue.number, ThreadKind::Issue, content,
+                     conf);
+    }
+}
+
+// Maximum number of changed lines to show when an issue body is edited
+static MAX_DIFF_LINES: usize = 10;
+
+// A short line diff: everything between the common leading & trailing lines
+fn short_diff(old: &str, new: &str) -> String {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    let prefix = old_lines.iter().zip(new_lines.iter())
+        .take_while(|&(o, n)| o == n)
+        .count();
+    let suffix = old_lines[prefix..].iter().rev()
+        .zip(new_lines[prefix..].iter().rev())
+        .take_while(|&(o, n)| o == n)
+        .count();
+    let removed = old_lines[prefix..old_lines.len() - suffix].iter()
+        .map(|l| format!("- {}", l));
+    let added = new_lines[prefix..new_lines.len() - suffix].iter()
+        .map(|l| format!("+ {}", l));
+    let changed: Vec<String> = removed.chain(added).collect();
+    let mut diff = changed.iter().take(MAX_DIFF_LINES)
+        .cloned()
+        .collect::<Vec<_>>()
+        .join("\n");
+    if changed.len() > MAX_DIFF_LINES {
+        diff.push_str(&format!("\n... ({} more lines)",
+                               changed.len() - MAX_DIFF_LINES)[..]);
+    }
+    diff
+}
+
+fn edited_issue_from_webhook(payload: IssuesEvent, conf: AppConf) {
+    let changes = match payload.changes {
+        Some(c) => c,
+        None => { println!("Edit without changes"); return }
+    };
+    let issue = payload.issue;
+    let mut msg_body = format!("{} edited the issue", payload.sender.login);
+    if let Some(old_title) = changes.title {
+        msg_body.push_str(
+            &format!("\ntitle changed from \"{}\" to \"{}\"",
+                     old_title.from, issue.title)[..]);
+    }
+    if let Some(old_body) = changes.body {
+        let new_body = issue.body.unwrap_or(String::new());
+        msg_body.push_str(
+            &format!("\ndescription changed:\n```\n{}\n```",
+                     short_diff(&old_body.from[..], &new_body[..]))[..]);
+    }
+    post_to_thread(payload.repository.full_name, issue.number, msg_body, &conf);
+}
+
+// One-line updates for label, assignee & milestone changes, if the repo
+// wants them announced
+fn issue_meta_from_webhook(payload: IssuesEvent, conf: AppConf) {
+    let repo_name = payload.repository.full_name;
+    let announce = find_repo_conf(&repo_name[..], &conf)
+        .map(|r| r.announce.contains(&payload.action))
+        .unwrap_or(false);
+    if !announce {
+        println!("Not announcing {} for {}", payload.action, repo_name);
+        return
+    }
+    let sender = payload.sender.login;
+    let label = payload.label.map(|l| l.name)
+        .unwrap_or("a label".to_owned());
+    let assignee = payload.assignee.map(|a| a.login)
+        .unwrap_or("someone".to_owned());
+    let milestone = payload.milestone.map(|m| m.title)
+        .unwrap_or("a milestone".to_owned());
+    let msg_body = match &payload.action[..] {
+        "labeled" => format!("{} added label {}", sender, label),
+        "unlabeled" => format!("{} removed label {}", sender, label),
+        "assigned" => format!("assigned to {} by {}", assignee, sender),
+        "unassigned" => format!("{} unassigned by {}", assignee, sender),
+        "milestoned" => format!("{} added milestone {}", sender, milestone),
+        _ => format!("{} removed milestone {}", sender, milestone),
+    };
+    post_to_thread(repo_name, payload.issue.number, msg_body, &conf);
+}
+
+fn issues_event(payload: IssuesEvent, conf: AppConf) {
+    match &payload.action.clone()[..] {
+        "opened" => new_issue_from_webhook(payload, conf),
+        "closed" => closed_issue_from_webhook(payload, conf),
+        "reopened" => reopened_issue_from_webhook(payload, conf),
+        "edited" => edited_issue_from_webhook(payload, conf),
+        "labeled" | "unlabeled" | "assigned" | "unassigned" | "milestoned"
+            | "demilestoned" => issue_meta_from_webhook(payload, conf),
+        action => println!("Unhandled issues action {}", action),
+    }
+}
+
+fn issue_comment_event(payload: IssueCommentEvent, conf: AppConf) {
+    match &payload.action.clone()[..] {
+        "created" => comment_from_webhook(payload, conf),
+        "edited" | "deleted" => changed_comment_from_webhook(payload, conf),
+        action => println!("Unhandled issue_comment action {}", action),
+    }
+}
+
+fn new_pull_request_from_webhook(payload: PullRequestEvent, conf: AppConf) {
+    let repo_name = payload.repository.full_name;
+    let pr = payload.pull_request;
+    if tracking::thread_for_issue(repo_name.clone(), pr.number, &conf)
+        .is_some()
+        {
+            println!("Already tracking this pull request");
+            return
+        }
+
+    let content = format!("{} opened pull request \"{}\"\n{}",
+                          pr.user.login, pr.title, pr.html_url);
+    start_thread(repo_name, pr.number, ThreadKind::PullRequest, content, conf);
+}
+
+// Post a PR status change to its thread, opening a thread for it first if
+// we weren't tracking it yet
+fn pull_request_update(payload: PullRequestEvent, update: &str, conf: AppConf) {
+    let repo_name = payload.repository.full_name;
+    let pr = payload.pull_request;
+    println!("Pull request {} in {} {}", pr.number, repo_name, update);
+    if payload.action == "reopened"
+        && is_our_state_change(&repo_name[..], pr.number, "open", &conf)
+    {
+        return
+    }
+    let msg_body = format!("pull request {} by {}", update, payload.sender.login);
+    if !post_to_thread(repo_name.clone(), pr.number, msg_body, &conf) {
+        let content = format!("pull request \"{}\" {} by {}\n{}",
+                              pr.title, update, payload.sender.login,
+                              pr.html_url);
+        start_thread(repo_name, pr.number, ThreadKind::PullRequest, content,
+                     conf);
+    }
+}
+
+fn closed_pull_request_from_webhook(payload: PullRequestEvent, conf: AppConf) {
+    let repo_name = payload.repository.full_name;
+    let pr = payload.pull_request;
+    println!("Pull request {} in {} closed", pr.number, repo_name);
+    if !pr.merged && is_our_state_change(&repo_name[..], pr.number, "closed", &conf) {
+        return
+    }
+    let msg_body = if pr.merged {
+        format!("pull request merged by {}", payload.sender.login)
+    } else {
+        format!("pull request closed by {}", payload.sender.login)
+    };
+    post_to_thread(repo_name, pr.number, msg_body, &conf);
+}
+
+fn pull_request_event(payload: PullRequestEvent, conf: AppConf) {
+    match &payload.action.clone()[..] {
+        "opened" => new_pull_request_from_webhook(payload, conf),
+        "closed" => closed_pull_request_from_webhook(payload, conf),
+        "reopened" => pull_request_update(payload, "reopened", conf),
+        "ready_for_review" =>
+            pull_request_update(payload, "marked ready for review", conf),
+        action => println!("Unhandled pull_request action {}", action),
+    }
+}
+
+fn review_from_webhook(payload: PullRequestReviewEvent, conf: AppConf) {
+    let review = payload.review;
+    let body = review.body.unwrap_or(String::new());
+    let msg_body = match &review.state.to_lowercase()[..] {
+        "approved" => format!("{} approved the pull request\n{}",
+                              review.user.login, body),
+        "changes_requested" => format!("{} requested changes:\n{}",
+                                       review.user.login, body),
+        _ => {
+            // Inline comments come with an empty "commented" review; the
+            // comments themselves get posted from their own webhooks
+            if body.trim().is_empty() {
+                println!("Skipping empty review {}", review.id);
+                return
+            }
+            format!("{} reviewed:\n{}", review.user.login, body)
+        }
+    };
+    post_to_thread(payload.repository.full_name, payload.pull_request.number,
+                   msg_body, &conf);
+}
+
+fn review_comment_from_webhook(payload: PullRequestReviewCommentEvent,
+                               conf: AppConf)
+{
+    let comment = payload.comment;
+    let location = match comment.line.or(comment.original_line) {
+        Some(line) => format!("`{}` line {}", comment.path, line),
+        None => format!("`{}`", comment.path),
+    };
+    let msg_body = format!("{} commented on {}:\n```\n{}\n```\n{}",
+                           comment.user.login, location, comment.diff_hunk,
+                           comment.body);
+    post_to_thread(payload.repository.full_name, payload.pull_request.number,
+                   msg_body, &conf);
+}
+
+fn pull_request_review_event(payload: PullRequestReviewEvent, conf: AppConf) {
+    match &payload.action.clone()[..] {
+        "submitted" => review_from_webhook(payload, conf),
+        action => println!("Unhandled pull_request_review action {}", action),
+    }
+}
+
+fn pull_request_review_comment_event(payload: PullRequestReviewCommentEvent,
+                                     conf: AppConf)
+{
+    match &payload.action.clone()[..] {
+        "created" => review_comment_from_webhook(payload, conf),
+        action => println!("Unhandled pull_request_review_comment action {}",
+                           action),
+    }
+}
+
+fn ping_event(payload: PingEvent) {
+    println!("Ping from webhook {}: {}", payload.hook_id, payload.zen);
+}
+
+// One log line with serde's description of the problem (e.g. the missing
+// field) per payload we fail to parse
+fn parse_event<T: Deserialize>(event: &str, delivery: &str, body: &[u8]) -> Option<T> {
+    match serde_json::from_slice(body) {
+        Ok(payload) => Some(payload),
+        Err(e) => {
+            println!("Couldn't parse {} payload from delivery {}: {}",
+                     event, delivery, e);
+            None
+        }
+    }
+}
+
+pub fn update_from_github(event: String, delivery: String, msg_body: Vec<u8>, conf: AppConf) {
+    println!("Got {} event from github, delivery {}", event, delivery);
+    let body = &msg_body[..];
+    let action = serde_json::from_slice::<JsonValue>(body).ok()
+        .and_then(|v| v.find("action").and_then(|a| a.as_string()).map(|a| a.to_owned()))
+        .unwrap_or(String::new());
+    metrics::inc("octocat_webhooks_received_total",
+                 &[("event", &event[..]), ("action", &action[..])]);
+    match &event[..] {
+        "issues" => {
+            if let Some(payload) = parse_event(&event, &delivery, body) {
+                issues_event(payload, conf)
+            }
+        }
+        "issue_comment" => {
+            if let Some(payload) = parse_event(&event, &delivery, body) {
+                issue_comment_event(payload, conf)
+            }
+        }
+        "pull_request" => {
+            if let Some(payload) = parse_event(&event, &delivery, body) {
+                pull_request_event(payload, conf)
+            }
+        }
+        "pull_request_review" => {
+            if let Some(payload) = parse_event(&event, &delivery, body) {
+                pull_request_review_event(payload, conf)
+            }
+        }
+        "pull_request_review_comment" => {
+            if let Some(payload) = parse_event(&event, &delivery, body) {
+                pull_request_review_comment_event(payload, conf)
+            }
+        }
+        "installation" => {
+            if let Some(payload) = parse_event(&event, &delivery, body) {
+                github_app::installation_event(payload, conf)
+            }
+        }
+        "installation_repositories" => {
+            if let Some(payload) = parse_event(&event, &delivery, body) {
+                github_app::installation_repositories_event(payload, conf)
             }
         }
+        "ping" => {
+            if let Some(payload) = parse_event(&event, &delivery, body) {
+                ping_event(payload)
+            }
+        }
+        _ => println!("Unhandled event type from webhook {}", event),
     }
 }
 
 pub fn update_from_braid(thread: tracking::WatchedThread, msg: message::Message, conf: AppConf)
 {
-    let comment_user = braid::get_user_nick(msg.user_id, &conf.braid)
+    let comment_user = credit_braid_user(msg.user_id, &conf)
         .unwrap_or("some braid user".to_owned());
 
     let repo_name = thread.repository;
@@ -261,39 +831,23 @@ pub fn update_from_braid(thread: tracking::WatchedThread, msg: message::Message,
             return
         }
     };
-    let token = repo_conf.token.clone();
-    let path = format!("/repos/{}/issues/{}/comments", repo_name,
-                       thread.issue_number);
-
-    let comment = format!("{} commented via [braid]({}):\n{}",
-    comment_user,
-    braid::thread_url(&conf.braid, &msg),
-    msg.content);
-    let mut map = Map::new();
-    map.insert(String::from("body"), JsonValue::String(comment));
-    let data = JsonValue::Object(map);
-    match send_github_request(&token[..], &path[..], data) {
-        Err(e) => println!("Error sending github request: {:?}", e),
-        Ok(mut resp) => {
-            let mut buf = String::new();
-            match resp.read_to_string(&mut buf) {
-                Err(e) => println!("Error reading github response: {:?}", e),
-                Ok(_) => {
-                    match serde_json::from_str(&buf[..]) {
-                        Err(e) => println!("Couldn't parse json from github: {:?}", e),
-                        Ok(new_comment) => {
-                            let new_comment: JsonValue = new_comment;
-                            if let Some(id) = new_comment.find("id").and_then(|i| i.as_i64()) {
-                                tracking::track_comment(msg.thread_id,
-                                                        id,
-                                                        &conf);
-                            } else {
-                                println!("Couldn't get comment id");
-                            }
-                        }
-                    }
-                }
-            }
+    // Users that have authorized us get the comment posted as them
+    let (token, comment) = match user_github_token(msg.user_id, &conf) {
+        Some(token) => (token, format!("{}\n\n_via [braid]({})_",
+                                       msg.content,
+                                       braid::thread_url(&conf.braid, &msg))),
+        None => (repo_conf.token.clone(),
+                 format!("{} commented via [braid]({}):\n{}",
+                         comment_user,
+                         braid::thread_url(&conf.braid, &msg),
+                         msg.content)),
+    };
+    match comment_on_issue(repo_conf, &token[..], thread.issue_number, comment) {
+        Some(new_comment) => {
+            tracking::track_comment(msg.thread_id, new_comment.id, &conf);
+            tracking::track_comment_message(new_comment.id, msg.thread_id,
+                                            msg.id, &conf);
         }
+        None => println!("Couldn't get comment id"),
     }
 }
@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::Mutex;
+use std::time::{SystemTime,UNIX_EPOCH};
+use hyper::method::Method;
+use openssl::crypto::pkey::PKey;
+use openssl::crypto::hash::Type;
+use rustc_serialize::base64::{ToBase64,URL_SAFE};
+use serde_json;
+use serde_json::value::{Value as JsonValue,Map};
+
+use app_conf::{AppConf,GithubAppConf,RepoConf,default_announce};
+use github;
+use github_types::{Installation,InstallationToken,InstallationRepositories,
+                   InstallationEvent,InstallationRepositoriesEvent};
+use tracking;
+
+// Installation tokens last an hour; refresh them a bit before that
+static TOKEN_LIFETIME_SECS: u64 = 50 * 60;
+
+lazy_static! {
+    // installation id -> (token, expiry time)
+    static ref TOKENS: Mutex<HashMap<i64, (String, u64)>> = Mutex::new(HashMap::new());
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// The JWT that authenticates us as the app itself, signed with its key
+fn app_jwt(app_conf: &GithubAppConf) -> Option<String> {
+    let mut key_reader = Cursor::new(&app_conf.private_key[..]);
+    let key = match PKey::private_key_from_pem(&mut key_reader) {
+        Ok(k) => k,
+        Err(e) => { println!("Couldn't load github app key: {:?}", e); return None }
+    };
+    let now = now_secs();
+    let mut header = Map::new();
+    header.insert(String::from("alg"), JsonValue::String(String::from("RS256")));
+    header.insert(String::from("typ"), JsonValue::String(String::from("JWT")));
+    let mut claims = Map::new();
+    // backdated a bit for clock drift; github allows at most ten minutes
+    claims.insert(String::from("iat"), JsonValue::U64(now - 60));
+    claims.insert(String::from("exp"), JsonValue::U64(now + 9 * 60));
+    claims.insert(String::from("iss"), JsonValue::I64(app_conf.app_id));
+    let mut jwt = format!(
+        "{}.{}",
+        serde_json::to_string(&JsonValue::Object(header))
+            .expect("Can't serialize data").as_bytes().to_base64(URL_SAFE),
+        serde_json::to_string(&JsonValue::Object(claims))
+            .expect("Can't serialize data").as_bytes().to_base64(URL_SAFE));
+    let signature = key.sign_with_hash(jwt.as_bytes(), Type::SHA256);
+    jwt.push_str(".");
+    jwt.push_str(&signature.to_base64(URL_SAFE)[..]);
+    Some(jwt)
+}
+
+// A token for acting on an installation's repos, minted as needed
+fn installation_token(app_conf: &GithubAppConf, installation_id: i64) -> Option<String> {
+    let now = now_secs();
+    if let Some(&(ref token, expires)) = TOKENS.lock().unwrap().get(&installation_id) {
+        if expires > now {
+            return Some(token.clone())
+        }
+    }
+    let jwt = match app_jwt(app_conf) {
+        Some(j) => j,
+        None => return None,
+    };
+    let path = format!("/app/installations/{}/access_tokens", installation_id);
+    let token: Option<InstallationToken> = github::parse_github_response(
+        github::send_github_request(Method::Post, &jwt[..], &path[..], None));
+    token.map(|t| {
+        TOKENS.lock().unwrap()
+            .insert(installation_id, (t.token.clone(), now + TOKEN_LIFETIME_SECS));
+        t.token
+    })
+}
+
+// Find all the repos the app is installed on
+pub fn discover_installations(conf: &AppConf) {
+    let app_conf = match conf.github.app {
+        Some(ref a) => a,
+        None => return,
+    };
+    let jwt = match app_jwt(app_conf) {
+        Some(j) => j,
+        None => return,
+    };
+    let installations: Option<Vec<Installation>> = github::parse_github_response(
+        github::send_github_request(Method::Get, &jwt[..], "/app/installations",
+                                    None));
+    for installation in installations.unwrap_or(vec![]) {
+        let token = match installation_token(app_conf, installation.id) {
+            Some(t) => t,
+            None => continue,
+        };
+        let repos: Option<InstallationRepositories> = github::parse_github_response(
+            github::send_github_request(Method::Get, &token[..],
+                                        "/installation/repositories", None));
+        for repo in repos.map(|r| r.repositories).unwrap_or(vec![]) {
+            tracking::add_installed_repo(&repo.full_name[..], installation.id, conf);
+        }
+    }
+}
+
+// Fill in installation tokens for repos without their own token, and add
+// repos the app has been installed on that aren't in the conf
+pub fn with_installation_tokens(mut conf: AppConf) -> AppConf {
+    let app_conf = match conf.github.app.clone() {
+        Some(a) => a,
+        None => return conf,
+    };
+    let installed: HashMap<String, i64> = tracking::installed_repos(&conf)
+        .into_iter().collect();
+    for repo in conf.repos.iter_mut().filter(|r| r.token.is_empty()) {
+        let full_name = format!("{}/{}", repo.org, repo.repo);
+        match installed.get(&full_name)
+            .and_then(|id| installation_token(&app_conf, *id)) {
+                Some(token) => repo.token = token,
+                None => println!("No installation token for {}", full_name),
+            }
+    }
+    if let Some(tag_id) = app_conf.default_tag_id {
+        for (full_name, installation_id) in installed {
+            if github::find_repo_conf(&full_name[..], &conf).is_some() {
+                continue
+            }
+            let (org, repo) = {
+                let mut split = full_name.splitn(2, '/');
+                (split.next().unwrap_or("").to_owned(),
+                 split.next().unwrap_or("").to_owned())
+            };
+            if let Some(token) = installation_token(&app_conf, installation_id) {
+                conf.repos.push(RepoConf {
+                    token: token,
+                    org: org,
+                    repo: repo,
+                    tag_id: tag_id,
+                    announce: default_announce(),
+                });
+            }
+        }
+    }
+    conf
+}
+
+pub fn installation_event(payload: InstallationEvent, conf: AppConf) {
+    let installation_id = payload.installation.id;
+    match &payload.action[..] {
+        "created" => {
+            for repo in payload.repositories {
+                tracking::add_installed_repo(&repo.full_name[..], installation_id,
+                                             &conf);
+            }
+        }
+        "deleted" => {
+            TOKENS.lock().unwrap().remove(&installation_id);
+            tracking::remove_installation(installation_id, &conf);
+        }
+        action => println!("Unhandled installation action {}", action),
+    }
+}
+
+pub fn installation_repositories_event(payload: InstallationRepositoriesEvent,
+                                       conf: AppConf)
+{
+    let installation_id = payload.installation.id;
+    for repo in payload.repositories_added {
+        tracking::add_installed_repo(&repo.full_name[..], installation_id, &conf);
+    }
+    for repo in payload.repositories_removed {
+        tracking::remove_installed_repo(&repo.full_name[..], &conf);
+    }
+}
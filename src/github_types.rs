@@ -0,0 +1,223 @@
+// Models for the bits of github's API & webhook payloads that we use.
+// Only the fields we actually look at are listed; serde ignores the rest.
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct User {
+    pub login: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Repository {
+    pub full_name: String,
+    pub html_url: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Label {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Milestone {
+    pub number: i64,
+    pub title: String,
+}
+
+// Present on issues that are actually pull requests
+#[derive(Debug, Clone, Deserialize)]
+pub struct PullRequestLinks {
+    pub html_url: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Issue {
+    pub number: i64,
+    pub title: String,
+    pub body: Option<String>,
+    pub html_url: String,
+    pub state: String,
+    pub user: User,
+    // api url of the issue's repo, e.g. https://api.github.com/repos/org/repo
+    #[serde(default)]
+    pub repository_url: String,
+    #[serde(default)]
+    pub labels: Vec<Label>,
+    #[serde(default)]
+    pub assignees: Vec<User>,
+    #[serde(default)]
+    pub comments: i64,
+    pub pull_request: Option<PullRequestLinks>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Comment {
+    pub id: i64,
+    pub body: String,
+    pub html_url: String,
+    pub user: User,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PullRequest {
+    pub number: i64,
+    pub title: String,
+    pub body: Option<String>,
+    pub html_url: String,
+    pub state: String,
+    pub user: User,
+    #[serde(default)]
+    pub merged: bool,
+    #[serde(default)]
+    pub draft: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Review {
+    pub id: i64,
+    pub body: Option<String>,
+    pub state: String,
+    pub html_url: String,
+    pub user: User,
+}
+
+// An inline comment on a PR's diff
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReviewComment {
+    pub id: i64,
+    pub body: String,
+    pub html_url: String,
+    pub user: User,
+    pub path: String,
+    pub line: Option<i64>,
+    pub original_line: Option<i64>,
+    pub diff_hunk: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Gist {
+    pub description: Option<String>,
+}
+
+// Response from exchanging an oauth code
+#[derive(Debug, Clone, Deserialize)]
+pub struct AccessToken {
+    pub access_token: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SearchResults {
+    pub total_count: i64,
+    pub items: Vec<Issue>,
+}
+
+// Github app installations
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Installation {
+    pub id: i64,
+}
+
+// Repos in installation payloads only have a few fields
+#[derive(Debug, Clone, Deserialize)]
+pub struct InstalledRepository {
+    pub full_name: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct InstallationRepositories {
+    pub repositories: Vec<InstalledRepository>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct InstallationToken {
+    pub token: String,
+}
+
+// The previous value of something changed in an "edited" event
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChangedFrom {
+    pub from: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct IssueChanges {
+    pub title: Option<ChangedFrom>,
+    pub body: Option<ChangedFrom>,
+}
+
+// Webhook payloads, one per X-GitHub-Event we handle
+
+#[derive(Debug, Deserialize)]
+pub struct IssuesEvent {
+    pub action: String,
+    pub issue: Issue,
+    pub repository: Repository,
+    pub sender: User,
+    pub changes: Option<IssueChanges>,
+    // Set for labeled/unlabeled, assigned/unassigned, milestoned/demilestoned
+    pub label: Option<Label>,
+    pub assignee: Option<User>,
+    pub milestone: Option<Milestone>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IssueCommentEvent {
+    pub action: String,
+    pub issue: Issue,
+    pub comment: Comment,
+    pub repository: Repository,
+    pub sender: User,
+    // Set for edited
+    pub changes: Option<IssueChanges>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PullRequestEvent {
+    pub action: String,
+    pub number: i64,
+    pub pull_request: PullRequest,
+    pub repository: Repository,
+    pub sender: User,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PullRequestReviewEvent {
+    pub action: String,
+    pub review: Review,
+    pub pull_request: PullRequest,
+    pub repository: Repository,
+    pub sender: User,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PullRequestReviewCommentEvent {
+    pub action: String,
+    pub comment: ReviewComment,
+    pub pull_request: PullRequest,
+    pub repository: Repository,
+    pub sender: User,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InstallationEvent {
+    pub action: String,
+    pub installation: Installation,
+    #[serde(default)]
+    pub repositories: Vec<InstalledRepository>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InstallationRepositoriesEvent {
+    pub action: String,
+    pub installation: Installation,
+    #[serde(default)]
+    pub repositories_added: Vec<InstalledRepository>,
+    #[serde(default)]
+    pub repositories_removed: Vec<InstalledRepository>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PingEvent {
+    pub zen: String,
+    pub hook_id: i64,
+}
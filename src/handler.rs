@@ -1,65 +1,105 @@
 use std::thread;
 use std::io::Read;
+use std::collections::{HashMap,BTreeMap};
 use iron::{Request,Response,IronError};
 use iron::status;
 use iron::error::HttpError;
 use openssl::crypto::hmac;
+use openssl::crypto::hash;
 use openssl::crypto::hash::Type;
 // to make from_hex on strings work
-use rustc_serialize::hex::FromHex;
+use rustc_serialize::hex::{FromHex,ToHex};
+use mime::{Mime,TopLevel,SubLevel};
+use serde_json;
 
 use app_conf::AppConf;
 use routing;
 use message;
 use commands;
 use github;
+use github_app;
+use metrics;
 use tracking;
 
+// Compare MACs without leaking how much of them matched through timing
+fn macs_equal(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 fn verify_braid_hmac(mac: Vec<u8>, key: &[u8], data: &[u8]) -> bool {
     if let Some(mac) = String::from_utf8(mac).ok()
         .and_then(|mac_str| (&mac_str[..]).from_hex().ok()) {
             let generated: Vec<u8> = hmac::hmac(Type::SHA256, key, data).to_vec();
-            mac == generated
+            macs_equal(&mac[..], &generated[..])
         } else {
             false
         }
 }
 
-fn verify_github_hmac(mac: Vec<u8>, key: &[u8], data: &[u8]) -> bool {
-    let sig_str = String::from_utf8(mac).ok().unwrap_or_default();
-    if let Some(mac) = sig_str
-        .splitn(2, '=').last()
+// Github signatures look like "<algorithm>=<hex mac>"
+fn verify_github_hmac(mac: Vec<u8>, algorithm: &str, hash: Type, key: &[u8],
+                      data: &[u8]) -> bool
+{
+    let sig_str = String::from_utf8(mac).unwrap_or(String::new());
+    let mut sig = sig_str.splitn(2, '=');
+    if sig.next() != Some(algorithm) {
+        return false
+    }
+    if let Some(mac) = sig.next()
         .and_then(|mac_str| (&mac_str[..]).from_hex().ok()) {
-        let generated: Vec<u8> = hmac::hmac(Type::SHA1, key, data).to_vec();
-        mac == generated
+        let generated: Vec<u8> = hmac::hmac(hash, key, data).to_vec();
+        macs_equal(&mac[..], &generated[..])
     } else {
         false
     }
 }
 
+fn header_string(request: &Request, header: &str) -> Option<String> {
+    request.headers.get_raw(header)
+        .and_then(|h| h.get(0))
+        .and_then(|v| String::from_utf8(v.clone()).ok())
+}
 
 pub fn handle_braid_message(request: &mut Request, conf: AppConf) -> Result<Response,IronError> {
     // Verify MAC
-    let mac = try!(request.headers.get_raw("X-Braid-Signature")
-                   .and_then(|h| h.get(0))
-                   .ok_or(IronError::new(routing::MissingMac,
-                                         status::Unauthorized)));
+    let mac = match request.headers.get_raw("X-Braid-Signature")
+        .and_then(|h| h.get(0)) {
+            Some(m) => m,
+            None => {
+                metrics::inc("octocat_mac_failures_total", &[("source", "braid")]);
+                return Err(IronError::new(routing::MissingMac, status::Unauthorized))
+            }
+        };
 
     let braid_token = conf.braid.token.clone();
     let mut buf = Vec::new();
     request.body.read_to_end(&mut buf).unwrap(); // TODO: check
     if !verify_braid_hmac(mac.clone(), braid_token.as_bytes(), &buf[..]) {
         println!("Bad mac");
+        metrics::inc("octocat_mac_failures_total", &[("source", "braid")]);
         return Err(IronError::new(routing::BadMac, status::Forbidden));
     }
     println!("Mac OK");
     match message::decode_transit_msgpack(buf) {
+        Some(ref msg) if !tracking::record_delivery(
+            "braid", &msg.id.simple().to_string()[..], &conf) => {
+            println!("Already handled message {}", msg.id);
+        }
         Some(msg) => {
+            metrics::inc("octocat_braid_messages_handled_total", &[]);
             thread::spawn(move || {
+                let conf = github_app::with_installation_tokens(conf);
                 if let Some(thread) = tracking::issue_for_thread(msg.thread_id,
                                                                  &conf)
                 {
-                    github::update_from_braid(thread, msg, conf);
+                    if commands::is_bot_command(&msg, &conf) {
+                        commands::parse_thread_command(thread, msg, conf);
+                    } else {
+                        github::update_from_braid(thread, msg, conf);
+                    }
                 } else {
                     commands::parse_command(msg, conf);
                 }
@@ -71,9 +111,22 @@ pub fn handle_braid_message(request: &mut Request, conf: AppConf) -> Result<Resp
 }
 
 pub fn handle_github_webhook(request: &mut Request, conf: AppConf) -> Result<Response,IronError> {
-    let mac = try!(request.headers.get_raw("X-Hub-Signature")
-                   .and_then(|h| h.get(0))
-                   .ok_or(IronError::new(routing::MissingMac, status::Unauthorized)));
+    // Prefer the SHA-256 signature; SHA-1 only if the conf allows it
+    let sha256_mac = request.headers.get_raw("X-Hub-Signature-256")
+        .and_then(|h| h.get(0))
+        .cloned();
+    let sha1_mac = request.headers.get_raw("X-Hub-Signature")
+        .and_then(|h| h.get(0))
+        .cloned();
+    let (mac, algorithm, hash_type) = match (sha256_mac, sha1_mac,
+                                             conf.github.allow_sha1) {
+        (Some(mac), _, _) => (mac, "sha256", Type::SHA256),
+        (None, Some(mac), true) => (mac, "sha1", Type::SHA1),
+        _ => {
+            metrics::inc("octocat_mac_failures_total", &[("source", "github")]);
+            return Err(IronError::new(routing::MissingMac, status::Unauthorized))
+        }
+    };
 
     let github_token = conf.github.webhook_secret.clone();
     let mut buf = Vec::new();
@@ -83,14 +136,184 @@ pub fn handle_github_webhook(request: &mut Request, conf: AppConf) -> Result<Res
             Err(IronError::new(HttpError::Io(e), status::BadRequest))
         }
         Ok(_) => {
-            if !verify_github_hmac(mac.clone(), github_token.as_bytes(), &buf[..]) {
+            if !verify_github_hmac(mac, algorithm, hash_type,
+                                   github_token.as_bytes(), &buf[..]) {
                 println!("Bad mac");
+                metrics::inc("octocat_mac_failures_total", &[("source", "github")]);
                 return Err(IronError::new(routing::BadMac, status::Forbidden));
             }
             println!("Mac OK");
 
-            thread::spawn(move || { github::update_from_github(buf, conf) });
+            let event = header_string(request, "X-GitHub-Event")
+                .unwrap_or("unknown".to_owned());
+            let delivery = header_string(request, "X-GitHub-Delivery")
+                .unwrap_or("unknown".to_owned());
+            // The signature only covers the body, so the delivery header can
+            // be dropped or changed on a replay; dedupe on the body too
+            let digest = hash::hash(Type::SHA256, &buf[..]).to_hex();
+            let new_body = tracking::record_delivery("github-body", &digest[..],
+                                                     &conf);
+            let new_delivery = delivery == "unknown"
+                || tracking::record_delivery("github", &delivery[..], &conf);
+            if !new_body || !new_delivery {
+                println!("Already handled delivery {} ({})", delivery, digest);
+                return Ok(Response::with((status::Ok, "ok")))
+            }
+            thread::spawn(move || {
+                let conf = github_app::with_installation_tokens(conf);
+                github::update_from_github(event, delivery, buf, conf)
+            });
             Ok(Response::with((status::Ok, "ok")))
         }
     }
 }
+
+fn query_params(request: &Request) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    if let Some(query) = request.url.query() {
+        for pair in query.split('&') {
+            let mut kv = pair.splitn(2, '=');
+            if let (Some(k), Some(v)) = (kv.next(), kv.next()) {
+                params.insert(k.to_owned(), v.to_owned());
+            }
+        }
+    }
+    params
+}
+
+pub fn handle_oauth_callback(request: &mut Request, conf: AppConf) -> Result<Response,IronError> {
+    let oauth_conf = match conf.oauth.clone() {
+        Some(c) => c,
+        None => return Err(IronError::new(routing::NoRoute, status::NotFound)),
+    };
+    let params = query_params(request);
+    let (code, state) = match (params.get("code"), params.get("state")) {
+        (Some(c), Some(s)) => (c, s),
+        _ => return Err(IronError::new(routing::BadOauth, status::BadRequest)),
+    };
+    let user_id = try!(tracking::finish_oauth(&state[..], &conf)
+                       .ok_or(IronError::new(routing::BadOauth, status::Forbidden)));
+    let token = try!(github::exchange_oauth_code(&oauth_conf, &code[..])
+                     .ok_or(IronError::new(routing::BadOauth, status::BadGateway)));
+    // Whoever followed the link might not be the braid user who asked for
+    // it, so only take a token for the github account they've already proven
+    let user = try!(github::token_user(&token[..])
+                    .ok_or(IronError::new(routing::BadOauth, status::BadGateway)));
+    let proven = github::github_login_for(user_id, &conf)
+        .map(|login| login.to_lowercase() == user.login.to_lowercase())
+        .unwrap_or(false);
+    if !proven {
+        println!("Refusing oauth token for {} from braid user {}", user.login, user_id);
+        return Err(IronError::new(
+            routing::BadOauth,
+            (status::Forbidden,
+             "This github account isn't the one linked to the braid account that asked to authorize")))
+    }
+    if !tracking::save_github_token(user_id, &token[..], &oauth_conf, &conf) {
+        return Err(IronError::new(routing::BadOauth, status::InternalServerError))
+    }
+    Ok(Response::with((status::Ok,
+                       "Your github account is connected to braid, you can close this page")))
+}
+
+// Process is up & serving requests
+pub fn handle_health(_request: &mut Request) -> Result<Response,IronError> {
+    Ok(Response::with((status::Ok, "ok")))
+}
+
+// Ready to handle webhooks: the database is usable & migrated
+pub fn handle_ready(_request: &mut Request, conf: AppConf) -> Result<Response,IronError> {
+    if tracking::schema_is_current(&conf) {
+        Ok(Response::with((status::Ok, "ok")))
+    } else {
+        Ok(Response::with((status::ServiceUnavailable, "database not ready")))
+    }
+}
+
+pub fn handle_version(_request: &mut Request, conf: AppConf) -> Result<Response,IronError> {
+    let mut info = BTreeMap::new();
+    info.insert("version", env!("CARGO_PKG_VERSION").to_owned());
+    info.insert("name", conf.braid.name.clone());
+    let body = serde_json::to_string(&info).expect("Can't serialize data");
+    Ok(Response::with((status::Ok,
+                       Mime(TopLevel::Application, SubLevel::Json, vec![]),
+                       body)))
+}
+
+pub fn handle_metrics(_request: &mut Request, conf: AppConf) -> Result<Response,IronError> {
+    let body = metrics::render(tracking::watched_thread_count(&conf));
+    Ok(Response::with((status::Ok,
+                       Mime(TopLevel::Text, SubLevel::Plain, vec![]),
+                       body)))
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::crypto::hash::Type;
+    use super::{macs_equal,verify_braid_hmac,verify_github_hmac};
+
+    // Github's sample from "Validating webhook deliveries"
+    static SECRET: &'static [u8] = b"It's a Secret to Everybody";
+    static PAYLOAD: &'static [u8] = b"Hello, World!";
+    static SHA256_SIG: &'static str =
+        "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+    static SHA1_SIG: &'static str = "sha1=01dc10d0c83e72ed246219cdd91669667fe2ca59";
+
+    #[test]
+    fn github_sha256_sample() {
+        assert!(verify_github_hmac(SHA256_SIG.as_bytes().to_vec(), "sha256",
+                                   Type::SHA256, SECRET, PAYLOAD));
+        assert!(!verify_github_hmac(SHA256_SIG.as_bytes().to_vec(), "sha256",
+                                    Type::SHA256, b"wrong secret", PAYLOAD));
+        assert!(!verify_github_hmac(SHA256_SIG.as_bytes().to_vec(), "sha256",
+                                    Type::SHA256, SECRET, b"Hello, World?"));
+    }
+
+    #[test]
+    fn github_sha1_sample() {
+        assert!(verify_github_hmac(SHA1_SIG.as_bytes().to_vec(), "sha1",
+                                   Type::SHA1, SECRET, PAYLOAD));
+    }
+
+    #[test]
+    fn github_wrong_algorithm_prefix() {
+        let relabelled = SHA256_SIG.replace("sha256=", "sha1=");
+        assert!(!verify_github_hmac(relabelled.into_bytes(), "sha256",
+                                    Type::SHA256, SECRET, PAYLOAD));
+        assert!(!verify_github_hmac(SHA1_SIG.as_bytes().to_vec(), "sha256",
+                                    Type::SHA256, SECRET, PAYLOAD));
+        let bare = SHA256_SIG.trim_left_matches("sha256=");
+        assert!(!verify_github_hmac(bare.as_bytes().to_vec(), "sha256",
+                                    Type::SHA256, SECRET, PAYLOAD));
+    }
+
+    #[test]
+    fn github_wrong_length_mac() {
+        let truncated = &SHA256_SIG[..SHA256_SIG.len() - 2];
+        assert!(!verify_github_hmac(truncated.as_bytes().to_vec(), "sha256",
+                                    Type::SHA256, SECRET, PAYLOAD));
+        let extended = format!("{}00", SHA256_SIG);
+        assert!(!verify_github_hmac(extended.into_bytes(), "sha256",
+                                    Type::SHA256, SECRET, PAYLOAD));
+    }
+
+    #[test]
+    fn macs_equal_lengths() {
+        assert!(macs_equal(&[1, 2, 3], &[1, 2, 3]));
+        assert!(!macs_equal(&[1, 2, 3], &[1, 2, 4]));
+        assert!(!macs_equal(&[1, 2], &[1, 2, 3]));
+        assert!(!macs_equal(&[1, 2, 3, 0], &[1, 2, 3]));
+        assert!(!macs_equal(&[], &[1]));
+    }
+
+    #[test]
+    fn braid_mac() {
+        let mac = b"7eb3ed1908c69943bf64993e0a309b13a5f66d70155aeb86456378897723b03a";
+        assert!(verify_braid_hmac(mac.to_vec(), b"braid-token", b"some message body"));
+        assert!(!verify_braid_hmac(mac.to_vec(), b"other-token", b"some message body"));
+        assert!(!verify_braid_hmac(mac[..62].to_vec(), b"braid-token",
+                                   b"some message body"));
+        assert!(!verify_braid_hmac(b"not hex".to_vec(), b"braid-token",
+                                   b"some message body"));
+    }
+}
@@ -33,10 +33,13 @@ mod conf;
 mod routing;
 mod message;
 mod github;
+mod github_types;
+mod github_app;
 mod braid;
 mod handler;
 mod commands;
 mod tracking;
+mod metrics;
 
 
 fn main() {
@@ -49,21 +52,38 @@ fn main() {
     let conf_filename = &args[1];
     let conf = app_conf::load_conf(&conf_filename[..]);
     tracking::setup_tables(&conf);
+    github_app::discover_installations(&conf);
     // Start server
-    let bind_addr = format!("localhost:{}", conf.general.port);
+    let bind_addr = format!("{}:{}", conf.general.bind_address, conf.general.port);
+    let tls = conf.general.tls.clone();
     println!("Bot {:?} starting", conf.braid.name);
-    Iron::new(move |request : &mut Request| {
+    let server = Iron::new(move |request : &mut Request| {
         let req_path = request.url.path().join("/");
         match request.method {
             method::Put => {
-                if req_path == "message" {
+                if req_path == conf.general.message_path {
                     handler::handle_braid_message(request, conf.clone())
                 } else {
                     Err(IronError::new(routing::NoRoute, status::NotFound))
                 }
             }
+            method::Get => {
+                if req_path == conf.general.oauth_callback_path {
+                    handler::handle_oauth_callback(request, conf.clone())
+                } else if req_path == "healthz" {
+                    handler::handle_health(request)
+                } else if req_path == "readyz" {
+                    handler::handle_ready(request, conf.clone())
+                } else if req_path == "version" {
+                    handler::handle_version(request, conf.clone())
+                } else if req_path == "metrics" {
+                    handler::handle_metrics(request, conf.clone())
+                } else {
+                    Err(IronError::new(routing::NoRoute, status::NotFound))
+                }
+            }
             method::Post => {
-                if req_path == "issue" {
+                if req_path == conf.general.issue_path {
                     handler::handle_github_webhook(request, conf.clone())
                 } else {
                     Err(IronError::new(routing::NoRoute, status::NotFound))
@@ -71,5 +91,9 @@ fn main() {
             }
             _ => Err(IronError::new(routing::NoRoute, status::NotFound))
         }
-    }).http(&bind_addr[..]).unwrap();
+    });
+    match tls {
+        Some(tls) => server.https(&bind_addr[..], tls.cert_path, tls.key_path).unwrap(),
+        None => server.http(&bind_addr[..]).unwrap(),
+    };
 }
@@ -26,6 +26,14 @@ pub struct Message {
     pub content: String,
 }
 
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+pub struct Thread {
+    #[serde(rename="~:id", deserialize_with="deserialize_transit_uuid")]
+    pub id: Uuid,
+    #[serde(rename="~:messages")]
+    pub messages: Vec<Message>,
+}
+
 type TransitUuid = (String, (i64, i64));
 
 fn deserialize_transit_uuid<D>(de: &mut D) -> Result<Uuid, D::Error>
@@ -108,6 +116,12 @@ pub fn decode_transit_msgpack(msgpack_buf: Vec<u8>) -> Option<Message> {
     Deserialize::deserialize(&mut deserializer).ok()
 }
 
+pub fn decode_transit_thread(msgpack_buf: Vec<u8>) -> Option<Thread> {
+    let cur = Cursor::new(&msgpack_buf[..]);
+    let mut deserializer = Deserializer::new(cur);
+    Deserialize::deserialize(&mut deserializer).ok()
+}
+
 pub fn response_to(msg: Message, content: String) -> Message {
     Message {
         id: Uuid::new_v4(),
@@ -132,7 +146,21 @@ pub fn new_thread_msg(tag: Uuid, content: String) -> Message {
     }
 }
 
-pub fn reply_to_thread(thread: Uuid, content: String) -> Message {
+// A new thread only the given user can see, for things the rest of the
+// group shouldn't
+pub fn private_msg(group: Uuid, user: Uuid, content: String) -> Message {
+    Message {
+        id: Uuid::new_v4(),
+        user_id: Uuid::new_v4(), // gets filled in by server
+        group_id: group,
+        thread_id: Uuid::new_v4(),
+        mentioned_user_ids: vec![user],
+        mentioned_tag_ids: vec![],
+        content: content,
+    }
+}
+
+pub fn reply_to_thread(thread: Uuid, content: String, mentions: Vec<Uuid>) -> Message {
     Message {
         id: Uuid::new_v4(),
         // user_id gets filled in by server
@@ -140,7 +168,7 @@ pub fn reply_to_thread(thread: Uuid, content: String) -> Message {
         // user_id gets filled in by server
         group_id: Uuid::new_v4(),
         thread_id: thread,
-        mentioned_user_ids: vec![],
+        mentioned_user_ids: mentions,
         mentioned_tag_ids: vec![],
         content: content,
     }
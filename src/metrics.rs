@@ -0,0 +1,147 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Instant;
+use hyper::client::Response;
+use hyper::error::Result as HttpResult;
+
+// Prometheus metrics, rendered in the text exposition format for /metrics
+
+// name, type, help
+static METRICS: &'static [(&'static str, &'static str, &'static str)] = &[
+    ("octocat_webhooks_received_total", "counter",
+     "Github webhooks received, by event and action"),
+    ("octocat_mac_failures_total", "counter",
+     "Requests with a missing or bad signature, by source"),
+    ("octocat_braid_messages_handled_total", "counter",
+     "Messages from braid handled"),
+    ("octocat_github_api_calls_total", "counter",
+     "Requests made to the github api, by response status"),
+    ("octocat_github_api_call_duration_seconds", "histogram",
+     "Time taken by requests to the github api"),
+    ("octocat_braid_api_calls_total", "counter",
+     "Requests made to the braid api, by response status"),
+    ("octocat_braid_api_call_duration_seconds", "histogram",
+     "Time taken by requests to the braid api"),
+    ("octocat_watched_threads", "gauge",
+     "Braid threads being synced with github"),
+];
+
+static BUCKETS: &'static [f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+struct Histogram {
+    // cumulative counts for each of BUCKETS
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+lazy_static! {
+    // metric name -> rendered labels -> value
+    static ref COUNTERS: Mutex<BTreeMap<&'static str, BTreeMap<String, u64>>> =
+        Mutex::new(BTreeMap::new());
+    static ref HISTOGRAMS: Mutex<BTreeMap<&'static str, BTreeMap<String, Histogram>>> =
+        Mutex::new(BTreeMap::new());
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    labels.iter()
+        .map(|&(k, v)| format!("{}=\"{}\"", k,
+                               v.replace("\\", "\\\\")
+                               .replace("\"", "\\\"")
+                               .replace("\n", "\\n")))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+pub fn inc(name: &'static str, labels: &[(&str, &str)]) {
+    let mut counters = COUNTERS.lock().unwrap();
+    *counters.entry(name).or_insert(BTreeMap::new())
+        .entry(render_labels(labels)).or_insert(0) += 1;
+}
+
+pub fn observe(name: &'static str, labels: &[(&str, &str)], value: f64) {
+    let mut histograms = HISTOGRAMS.lock().unwrap();
+    let histogram = histograms.entry(name).or_insert(BTreeMap::new())
+        .entry(render_labels(labels))
+        .or_insert(Histogram { buckets: vec![0; BUCKETS.len()], sum: 0.0, count: 0 });
+    for (i, bound) in BUCKETS.iter().enumerate() {
+        if value <= *bound {
+            histogram.buckets[i] += 1;
+        }
+    }
+    histogram.sum += value;
+    histogram.count += 1;
+}
+
+// Make an api request, counting it by status & timing it. Service is
+// "github" or "braid".
+pub fn time_request<F>(service: &'static str, request: F) -> HttpResult<Response>
+    where F: FnOnce() -> HttpResult<Response>
+{
+    let (calls, duration) = match service {
+        "github" => ("octocat_github_api_calls_total",
+                     "octocat_github_api_call_duration_seconds"),
+        _ => ("octocat_braid_api_calls_total",
+              "octocat_braid_api_call_duration_seconds"),
+    };
+    let start = Instant::now();
+    let resp = request();
+    let elapsed = start.elapsed();
+    observe(duration, &[],
+            elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9);
+    let status = match resp {
+        Ok(ref r) => r.status.to_u16().to_string(),
+        Err(_) => "error".to_owned(),
+    };
+    inc(calls, &[("status", &status[..])]);
+    resp
+}
+
+fn with_labels(name: &str, labels: &str, extra: &str) -> String {
+    match (labels.is_empty(), extra.is_empty()) {
+        (true, true) => name.to_owned(),
+        (false, true) => format!("{}{{{}}}", name, labels),
+        (true, false) => format!("{}{{{}}}", name, extra),
+        (false, false) => format!("{}{{{},{}}}", name, labels, extra),
+    }
+}
+
+pub fn render(watched_threads: Option<i64>) -> String {
+    let counters = COUNTERS.lock().unwrap();
+    let histograms = HISTOGRAMS.lock().unwrap();
+    let mut out = String::new();
+    for &(name, kind, help) in METRICS {
+        out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind)[..]);
+        if let Some(values) = counters.get(name) {
+            for (labels, value) in values {
+                out.push_str(&format!("{} {}\n", with_labels(name, labels, ""), value)[..]);
+            }
+        }
+        if let Some(values) = histograms.get(name) {
+            for (labels, histogram) in values {
+                let bucket_name = format!("{}_bucket", name);
+                for (bound, count) in BUCKETS.iter().zip(histogram.buckets.iter()) {
+                    let le = format!("le=\"{}\"", bound);
+                    out.push_str(&format!("{} {}\n",
+                                          with_labels(&bucket_name[..], labels, &le[..]),
+                                          count)[..]);
+                }
+                out.push_str(&format!("{} {}\n",
+                                      with_labels(&bucket_name[..], labels, "le=\"+Inf\""),
+                                      histogram.count)[..]);
+                out.push_str(&format!("{} {}\n",
+                                      with_labels(&format!("{}_sum", name)[..], labels, ""),
+                                      histogram.sum)[..]);
+                out.push_str(&format!("{} {}\n",
+                                      with_labels(&format!("{}_count", name)[..], labels, ""),
+                                      histogram.count)[..]);
+            }
+        }
+        if name == "octocat_watched_threads" {
+            if let Some(n) = watched_threads {
+                out.push_str(&format!("{} {}\n", name, n)[..]);
+            }
+        }
+    }
+    out
+}
@@ -40,3 +40,16 @@ impl fmt::Display for BadMac {
 impl Error for BadMac {
     fn description(&self) -> &str { "Bad signature header" }
 }
+
+#[derive(Debug)]
+pub struct BadOauth;
+
+impl fmt::Display for BadOauth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("OAuth callback failed")
+    }
+}
+
+impl Error for BadOauth {
+    fn description(&self) -> &str { "Bad oauth callback" }
+}
@@ -1,17 +1,127 @@
+use std::time::{SystemTime,UNIX_EPOCH};
 use uuid::Uuid;
 use rusqlite::Connection;
+use openssl::crypto::symm;
+use openssl::crypto::rand::rand_bytes;
+use rustc_serialize::hex::{ToHex,FromHex};
 
-use app_conf::AppConf;
+use app_conf::{AppConf,OauthConf};
 
 fn get_conn(conf: &AppConf) -> Connection {
     Connection::open(&conf.general.db_name[..])
         .expect("Couldn't open database!")
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ThreadKind {
+    Issue,
+    PullRequest,
+}
+
+impl ThreadKind {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            ThreadKind::Issue => "issue",
+            ThreadKind::PullRequest => "pull_request",
+        }
+    }
+
+    fn from_str(kind: &str) -> ThreadKind {
+        match kind {
+            "pull_request" => ThreadKind::PullRequest,
+            _ => ThreadKind::Issue,
+        }
+    }
+}
+
 pub struct WatchedThread {
     pub thread_id: Uuid,
     pub issue_number: i64,
     pub repository: String,
+    pub kind: ThreadKind,
+    // muted threads keep their link, but don't get github events posted
+    pub muted: bool,
+}
+
+// Schema changes made after the initial tables were created. They're applied
+// in order, with sqlite's user_version recording how many have been run.
+static MIGRATIONS: &'static [&'static str] = &[
+    "ALTER TABLE watched_threads ADD COLUMN kind TEXT NOT NULL DEFAULT 'issue';",
+    "CREATE TABLE comment_messages (
+      comment_id INTEGER NOT NULL UNIQUE,
+      thread_id TEXT NOT NULL,
+      message_id TEXT NOT NULL
+     );",
+    "CREATE TABLE state_changes (
+      thread_id TEXT NOT NULL,
+      state TEXT NOT NULL
+     );",
+    "ALTER TABLE watched_threads ADD COLUMN muted INTEGER NOT NULL DEFAULT 0;",
+    "CREATE TABLE github_users (
+      user_id TEXT NOT NULL UNIQUE,
+      login TEXT NOT NULL
+     );
+     CREATE TABLE pending_github_users (
+      user_id TEXT NOT NULL UNIQUE,
+      login TEXT NOT NULL,
+      code TEXT NOT NULL
+     );",
+    "CREATE TABLE oauth_states (
+      state TEXT NOT NULL UNIQUE,
+      user_id TEXT NOT NULL
+     );
+     CREATE TABLE github_tokens (
+      user_id TEXT NOT NULL UNIQUE,
+      iv TEXT NOT NULL,
+      token TEXT NOT NULL
+     );",
+    "CREATE TABLE installed_repos (
+      repository TEXT NOT NULL UNIQUE,
+      installation_id INTEGER NOT NULL
+     );",
+    "CREATE TABLE processed_deliveries (
+      source TEXT NOT NULL,
+      delivery_id TEXT NOT NULL,
+      received_at INTEGER NOT NULL
+     );
+     CREATE UNIQUE INDEX delivery_idx
+      ON processed_deliveries (source, delivery_id);",
+    // existing states get created_at 0, so they've already expired
+    "ALTER TABLE oauth_states ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;",
+];
+
+// How long an oauth link is good for
+static OAUTH_STATE_LIFETIME_SECS: i64 = 10 * 60;
+
+fn now_secs() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+// Can we open the database, and has it had all the migrations applied?
+pub fn schema_is_current(conf: &AppConf) -> bool {
+    match Connection::open(&conf.general.db_name[..])
+        .and_then(|conn| conn.query_row("PRAGMA user_version", &[],
+                                        |row| row.get::<_, i64>(0))) {
+        Ok(version) => version == MIGRATIONS.len() as i64,
+        Err(e) => {
+            println!("Couldn't check database: {:?}", e);
+            false
+        }
+    }
+}
+
+pub fn watched_thread_count(conf: &AppConf) -> Option<i64> {
+    match Connection::open(&conf.general.db_name[..])
+        .and_then(|conn| conn.query_row("SELECT COUNT(*) FROM watched_threads", &[],
+                                        |row| row.get::<_, i64>(0))) {
+        Ok(n) => Some(n),
+        Err(e) => {
+            println!("Couldn't count watched threads: {:?}", e);
+            None
+        }
+    }
 }
 
 pub fn setup_tables(conf: &AppConf) {
@@ -32,21 +142,38 @@ pub fn setup_tables(conf: &AppConf) {
                          ON watched_threads (repository, issue_number);
                         COMMIT;")
         .expect("Couldn't create the table");
+    let version = conn.query_row("PRAGMA user_version", &[],
+                                 |row| row.get::<_, i64>(0))
+        .expect("Couldn't get schema version");
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        conn.execute_batch(&format!("BEGIN;
+                                     {}
+                                     PRAGMA user_version = {};
+                                     COMMIT;", migration, i + 1)[..])
+            .expect("Couldn't migrate the database");
+    }
 }
 
 pub fn add_watched_thread(thread_id: Uuid,
                           repo: String,
                           issue_number: i64,
-                          conf: &AppConf)
+                          kind: ThreadKind,
+                          conf: &AppConf) -> bool
 {
     let conn = get_conn(conf);
 
-    match conn.execute("INSERT INTO watched_threads (thread_id, issue_number, repository)
-                  VALUES ($1, $2, $3)",
-                  &[&thread_id.simple().to_string(), &issue_number, &repo]) {
-        Ok(_) => { println!("Watching thread {}, {}, {}", thread_id, repo, issue_number); }
-        Err(e) => { println!("Couldn't save watched thread {} {} {}: {:?}",
-                             thread_id, repo, issue_number, e);
+    match conn.execute("INSERT INTO watched_threads (thread_id, issue_number, repository, kind)
+                  VALUES ($1, $2, $3, $4)",
+                  &[&thread_id.simple().to_string(), &issue_number, &repo,
+                    &kind.as_str()]) {
+        Ok(_) => {
+            println!("Watching thread {}, {}, {}", thread_id, repo, issue_number);
+            true
+        }
+        Err(e) => {
+            println!("Couldn't save watched thread {} {} {}: {:?}",
+                     thread_id, repo, issue_number, e);
+            false
         }
     }
 
@@ -56,16 +183,19 @@ pub fn thread_for_issue(repo: String, issue_number: i64, conf: &AppConf) -> Opti
 {
     let conn = get_conn(conf);
 
-    match conn.query_row("SELECT thread_id FROM watched_threads
+    match conn.query_row("SELECT thread_id, kind, muted FROM watched_threads
                     WHERE repository = $0 AND issue_number = $1",
                     &[&repo, &issue_number],
-                    |row| row.get::<_, String>(0)) {
-        Ok(thread_id) => Uuid::parse_str(&thread_id[..])
+                    |row| (row.get::<_, String>(0), row.get::<_, String>(1),
+                           row.get::<_, i64>(2))) {
+        Ok((thread_id, kind, muted)) => Uuid::parse_str(&thread_id[..])
             .ok()
             .map(|t_id| WatchedThread {
                 thread_id: t_id,
                 repository: repo,
                 issue_number: issue_number,
+                kind: ThreadKind::from_str(&kind[..]),
+                muted: muted != 0,
             }),
         Err(e) => {
             println!("Couldn't find thread for issue: {:?}", e);
@@ -79,13 +209,15 @@ pub fn issue_for_thread(thread_id: Uuid, conf: &AppConf) -> Option<WatchedThread
     let conn = get_conn(conf);
 
     match conn.query_row(
-        "SELECT issue_number, repository FROM watched_threads
+        "SELECT issue_number, repository, kind, muted FROM watched_threads
          WHERE thread_id = $0",
          &[&thread_id.simple().to_string()],
          |row| WatchedThread {
              thread_id: thread_id,
              issue_number: row.get::<_, i64>(0),
              repository: row.get::<_, String>(1),
+             kind: ThreadKind::from_str(&row.get::<_, String>(2)[..]),
+             muted: row.get::<_, i64>(3) != 0,
          })
     {
         Ok(issue) => Some(issue),
@@ -96,6 +228,39 @@ pub fn issue_for_thread(thread_id: Uuid, conf: &AppConf) -> Option<WatchedThread
     }
 }
 
+// Stop syncing a thread entirely, forgetting everything about it
+pub fn remove_watched_thread(thread_id: Uuid, conf: &AppConf) -> bool {
+    let conn = get_conn(conf);
+    let thread_id = thread_id.simple().to_string();
+
+    let removed = conn.transaction().and_then(|tx| {
+        try!(conn.execute("DELETE FROM watched_threads WHERE thread_id = $1",
+                          &[&thread_id]));
+        try!(conn.execute("DELETE FROM posted_comments WHERE thread_id = $1",
+                          &[&thread_id]));
+        try!(conn.execute("DELETE FROM comment_messages WHERE thread_id = $1",
+                          &[&thread_id]));
+        try!(conn.execute("DELETE FROM state_changes WHERE thread_id = $1",
+                          &[&thread_id]));
+        tx.commit()
+    });
+    match removed {
+        Ok(_) => { println!("Stopped watching thread {}", thread_id); true }
+        Err(e) => { println!("Couldn't remove watched thread: {:?}", e); false }
+    }
+}
+
+pub fn set_thread_muted(thread_id: Uuid, muted: bool, conf: &AppConf) -> bool {
+    let conn = get_conn(conf);
+    let muted: i64 = if muted { 1 } else { 0 };
+
+    match conn.execute("UPDATE watched_threads SET muted = $1 WHERE thread_id = $2",
+                       &[&muted, &thread_id.simple().to_string()]) {
+        Ok(_) => true,
+        Err(e) => { println!("Couldn't update thread muting: {:?}", e); false }
+    }
+}
+
 pub fn track_comment(thread_id: Uuid, comment_id: i64, conf: &AppConf) {
     let conn = get_conn(conf);
 
@@ -118,3 +283,282 @@ pub fn did_we_post_comment(thread_id: Uuid, comment_id: i64, conf: &AppConf) ->
         Err(_) => false,
     }
 }
+
+// Remember that we opened/closed an issue from its thread, so we don't
+// announce the webhook for it again
+pub fn track_state_change(thread_id: Uuid, state: &str, conf: &AppConf) {
+    let conn = get_conn(conf);
+
+    match conn.execute("INSERT INTO state_changes (thread_id, state)
+                        VALUES ($1, $2)",
+                        &[&thread_id.simple().to_string(), &state]) {
+        Ok(_) => { println!("Tracking state change to {} from {}", state, thread_id); },
+        Err(e) => { println!("Couldn't track state change: {:?}", e); }
+    }
+}
+
+// Checks for (and clears) a state change we made ourselves
+pub fn did_we_change_state(thread_id: Uuid, state: &str, conf: &AppConf) -> bool {
+    let conn = get_conn(conf);
+    match conn.execute("DELETE FROM state_changes
+                        WHERE thread_id = $0 AND state = $1",
+                       &[&thread_id.simple().to_string(), &state]) {
+        Ok(n) => n > 0,
+        Err(e) => {
+            println!("Couldn't check state changes: {:?}", e);
+            false
+        }
+    }
+}
+
+// Remember which braid message in a thread mirrors a github comment
+pub fn track_comment_message(comment_id: i64, thread_id: Uuid, message_id: Uuid,
+                             conf: &AppConf)
+{
+    let conn = get_conn(conf);
+
+    match conn.execute("INSERT INTO comment_messages (comment_id, thread_id, message_id)
+                        VALUES ($1, $2, $3)",
+                        &[&comment_id, &thread_id.simple().to_string(),
+                          &message_id.simple().to_string()]) {
+        Ok(_) => { println!("Comment {} is message {}", comment_id, message_id); },
+        Err(e) => { println!("Couldn't track comment message: {:?}", e); }
+    }
+}
+
+pub fn message_for_comment(comment_id: i64, conf: &AppConf) -> Option<Uuid> {
+    let conn = get_conn(conf);
+
+    match conn.query_row("SELECT message_id FROM comment_messages
+                          WHERE comment_id = $0",
+                         &[&comment_id],
+                         |row| row.get::<_, String>(0)) {
+        Ok(message_id) => Uuid::parse_str(&message_id[..]).ok(),
+        Err(e) => {
+            println!("Couldn't find message for comment: {:?}", e);
+            None
+        }
+    }
+}
+
+pub fn forget_comment_message(comment_id: i64, conf: &AppConf) {
+    let conn = get_conn(conf);
+
+    if let Err(e) = conn.execute("DELETE FROM comment_messages WHERE comment_id = $0",
+                                 &[&comment_id]) {
+        println!("Couldn't forget comment message: {:?}", e);
+    }
+}
+
+// Start verifying that a braid user owns a github account, returning the code
+// they need to prove it with
+pub fn start_github_verification(user_id: Uuid, login: &str, conf: &AppConf) -> Option<String> {
+    let conn = get_conn(conf);
+    let code = Uuid::new_v4().simple().to_string();
+
+    match conn.execute("INSERT OR REPLACE INTO pending_github_users (user_id, login, code)
+                        VALUES ($1, $2, $3)",
+                       &[&user_id.simple().to_string(), &login, &code]) {
+        Ok(_) => Some(code),
+        Err(e) => { println!("Couldn't save pending github user: {:?}", e); None }
+    }
+}
+
+// The github login & code a braid user is in the middle of verifying
+pub fn pending_github_verification(user_id: Uuid, conf: &AppConf) -> Option<(String, String)> {
+    let conn = get_conn(conf);
+
+    match conn.query_row("SELECT login, code FROM pending_github_users
+                          WHERE user_id = $0",
+                         &[&user_id.simple().to_string()],
+                         |row| (row.get::<_, String>(0), row.get::<_, String>(1))) {
+        Ok(pending) => Some(pending),
+        Err(_) => None,
+    }
+}
+
+pub fn set_github_login(user_id: Uuid, login: &str, conf: &AppConf) -> bool {
+    let conn = get_conn(conf);
+    let user_id = user_id.simple().to_string();
+
+    match conn.execute("INSERT OR REPLACE INTO github_users (user_id, login)
+                        VALUES ($1, $2)", &[&user_id, &login])
+        .and_then(|_| conn.execute("DELETE FROM pending_github_users
+                                    WHERE user_id = $0", &[&user_id])) {
+        Ok(_) => { println!("Braid user {} is github user {}", user_id, login); true }
+        Err(e) => { println!("Couldn't save github user: {:?}", e); false }
+    }
+}
+
+pub fn github_login_for_user(user_id: Uuid, conf: &AppConf) -> Option<String> {
+    let conn = get_conn(conf);
+
+    match conn.query_row("SELECT login FROM github_users WHERE user_id = $0",
+                         &[&user_id.simple().to_string()],
+                         |row| row.get::<_, String>(0)) {
+        Ok(login) => Some(login),
+        Err(_) => None,
+    }
+}
+
+pub fn braid_user_for_login(login: &str, conf: &AppConf) -> Option<Uuid> {
+    let conn = get_conn(conf);
+
+    match conn.query_row("SELECT user_id FROM github_users
+                          WHERE login = $0 COLLATE NOCASE",
+                         &[&login],
+                         |row| row.get::<_, String>(0)) {
+        Ok(user_id) => Uuid::parse_str(&user_id[..]).ok(),
+        Err(_) => None,
+    }
+}
+
+// Start an oauth flow for a braid user, returning the state to send to github
+pub fn start_oauth(user_id: Uuid, conf: &AppConf) -> Option<String> {
+    let conn = get_conn(conf);
+    let state = Uuid::new_v4().simple().to_string();
+    let now = now_secs();
+    let user = user_id.simple().to_string();
+
+    // Clear out expired states, and any earlier link for this user
+    if let Err(e) = conn.execute("DELETE FROM oauth_states
+                                  WHERE created_at < $1 OR user_id = $2",
+                                 &[&(now - OAUTH_STATE_LIFETIME_SECS), &user]) {
+        println!("Couldn't prune oauth states: {:?}", e);
+    }
+    match conn.execute("INSERT INTO oauth_states (state, user_id, created_at)
+                        VALUES ($1, $2, $3)",
+                       &[&state, &user, &now]) {
+        Ok(_) => Some(state),
+        Err(e) => { println!("Couldn't save oauth state: {:?}", e); None }
+    }
+}
+
+// Which braid user started the oauth flow; each state can only be used once,
+// and only for OAUTH_STATE_LIFETIME_SECS
+pub fn finish_oauth(state: &str, conf: &AppConf) -> Option<Uuid> {
+    let conn = get_conn(conf);
+    let cutoff = now_secs() - OAUTH_STATE_LIFETIME_SECS;
+
+    let user_id = conn.query_row("SELECT user_id FROM oauth_states
+                                  WHERE state = $1 AND created_at >= $2",
+                                 &[&state, &cutoff],
+                                 |row| row.get::<_, String>(0))
+        .ok()
+        .and_then(|u| Uuid::parse_str(&u[..]).ok());
+    if let Err(e) = conn.execute("DELETE FROM oauth_states WHERE state = $0", &[&state]) {
+        println!("Couldn't clear oauth state: {:?}", e);
+    }
+    user_id
+}
+
+// Tokens are stored encrypted with the key from the oauth conf
+pub fn save_github_token(user_id: Uuid, token: &str, oauth_conf: &OauthConf,
+                         conf: &AppConf) -> bool
+{
+    let conn = get_conn(conf);
+    let iv = rand_bytes(16);
+    let encrypted = symm::encrypt(symm::Type::AES_256_CBC,
+                                  &oauth_conf.encryption_key[..], &iv[..],
+                                  token.as_bytes());
+
+    match conn.execute("INSERT OR REPLACE INTO github_tokens (user_id, iv, token)
+                        VALUES ($1, $2, $3)",
+                       &[&user_id.simple().to_string(), &iv.to_hex(),
+                         &encrypted.to_hex()]) {
+        Ok(_) => { println!("Saved github token for {}", user_id); true }
+        Err(e) => { println!("Couldn't save github token: {:?}", e); false }
+    }
+}
+
+pub fn github_token_for_user(user_id: Uuid, oauth_conf: &OauthConf,
+                             conf: &AppConf) -> Option<String>
+{
+    let conn = get_conn(conf);
+
+    conn.query_row("SELECT iv, token FROM github_tokens WHERE user_id = $0",
+                   &[&user_id.simple().to_string()],
+                   |row| (row.get::<_, String>(0), row.get::<_, String>(1)))
+        .ok()
+        .and_then(|(iv, token)| {
+            match (iv.from_hex(), token.from_hex()) {
+                (Ok(iv), Ok(token)) => {
+                    let decrypted = symm::decrypt(symm::Type::AES_256_CBC,
+                                                  &oauth_conf.encryption_key[..],
+                                                  &iv[..], &token[..]);
+                    String::from_utf8(decrypted).ok()
+                }
+                _ => { println!("Bad github token for {}", user_id); None }
+            }
+        })
+}
+
+// Repos our github app is installed on, and through which installation
+pub fn add_installed_repo(repo: &str, installation_id: i64, conf: &AppConf) {
+    let conn = get_conn(conf);
+
+    match conn.execute("INSERT OR REPLACE INTO installed_repos (repository, installation_id)
+                        VALUES ($1, $2)",
+                       &[&repo, &installation_id]) {
+        Ok(_) => { println!("Installed on {} ({})", repo, installation_id); }
+        Err(e) => { println!("Couldn't save installed repo: {:?}", e); }
+    }
+}
+
+pub fn remove_installed_repo(repo: &str, conf: &AppConf) {
+    let conn = get_conn(conf);
+
+    if let Err(e) = conn.execute("DELETE FROM installed_repos WHERE repository = $0",
+                                 &[&repo]) {
+        println!("Couldn't remove installed repo: {:?}", e);
+    }
+}
+
+pub fn remove_installation(installation_id: i64, conf: &AppConf) {
+    let conn = get_conn(conf);
+
+    if let Err(e) = conn.execute("DELETE FROM installed_repos WHERE installation_id = $0",
+                                 &[&installation_id]) {
+        println!("Couldn't remove installation: {:?}", e);
+    }
+}
+
+pub fn installed_repos(conf: &AppConf) -> Vec<(String, i64)> {
+    let conn = get_conn(conf);
+
+    let mut stmt = match conn.prepare("SELECT repository, installation_id FROM installed_repos") {
+        Ok(stmt) => stmt,
+        Err(e) => { println!("Couldn't get installed repos: {:?}", e); return vec![] }
+    };
+    let repos = match stmt.query_map(&[], |row| (row.get::<_, String>(0),
+                                                 row.get::<_, i64>(1))) {
+        Ok(rows) => rows.filter_map(|r| r.ok()).collect(),
+        Err(e) => { println!("Couldn't get installed repos: {:?}", e); vec![] }
+    };
+    repos
+}
+
+// Record a webhook delivery (a github delivery id or braid message id),
+// returning false if we've already seen it. Deliveries are only remembered
+// for the configured retention window.
+pub fn record_delivery(source: &str, delivery_id: &str, conf: &AppConf) -> bool {
+    let conn = get_conn(conf);
+    let now = now_secs();
+    let cutoff = now - conf.general.delivery_retention_secs;
+
+    if let Err(e) = conn.execute("DELETE FROM processed_deliveries WHERE received_at < $0",
+                                 &[&cutoff]) {
+        println!("Couldn't prune old deliveries: {:?}", e);
+    }
+    match conn.execute("INSERT OR IGNORE INTO processed_deliveries
+                        (source, delivery_id, received_at)
+                        VALUES ($1, $2, $3)",
+                       &[&source, &delivery_id, &now]) {
+        Ok(n) => n > 0,
+        Err(e) => {
+            // better to risk a duplicate than to drop the delivery
+            println!("Couldn't record delivery: {:?}", e);
+            true
+        }
+    }
+}